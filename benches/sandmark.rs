@@ -0,0 +1,79 @@
+//! Times a full run of a scroll through `spin`, one cycle per call and
+//! decoding every platter as the machine did before the decode cache, then
+//! through `Machine::run` with and without the decode cache, and on the
+//! threaded engine and JIT when built with `--features "threaded jit"`.
+//! Speedups are relative to `spin`.
+//!
+//! `cargo bench --bench sandmark [--features "threaded jit"] [-- path/to/scroll.umz]`
+
+use std::env;
+use std::fs::File;
+use std::time::{Duration, Instant};
+
+use cbv::io::BufferIo;
+use cbv::{Machine, RunLimit};
+
+fn time_run(path: &str, configure: impl FnOnce(&mut Machine)) -> (Duration, u64) {
+    let mut scroll = File::open(path).expect("Could not open scroll");
+    let mut machine = Machine::new(BufferIo::default(), &mut scroll);
+    configure(&mut machine);
+    let start = Instant::now();
+    let outcome = machine.run(RunLimit::default());
+    let elapsed = start.elapsed();
+    println!("{:?} after {} cycles", outcome, machine.cycles());
+    #[cfg(feature = "threaded")]
+    println!("{:?}", machine.threaded_stats());
+    #[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+    println!("{:?}", machine.jit_stats());
+    (elapsed, machine.cycles())
+}
+
+#[allow(deprecated)]
+fn time_spin(path: &str) -> (Duration, u64) {
+    let mut scroll = File::open(path).expect("Could not open scroll");
+    let mut machine = Machine::new(BufferIo::default(), &mut scroll);
+    machine.set_decode_cache(false);
+    let start = Instant::now();
+    let mut cycles = 1;
+    while let Some(next) = cbv::spin(machine) {
+        machine = next;
+        cycles += 1;
+    }
+    let elapsed = start.elapsed();
+    println!("Halted after {} cycles", cycles);
+    (elapsed, cycles)
+}
+
+fn main() {
+    let path = env::args()
+        .skip(1)
+        .find(|a| !a.starts_with("--"))
+        .unwrap_or_else(|| String::from("static/media/sandmark.umz"));
+
+    let report = |name: &str, plain: Duration, elapsed: Duration, cycles: u64| {
+        println!(
+            "{:<19} {:?}, {:.2}x speedup, {:.1} Mcycles/s",
+            name,
+            elapsed,
+            plain.as_secs_f64() / elapsed.as_secs_f64(),
+            cycles as f64 / elapsed.as_secs_f64() / 1e6
+        )
+    };
+
+    let (plain, cycles) = time_spin(&path);
+    report("spin:", plain, plain, cycles);
+    let (uncached, _) = time_run(&path, |m| m.set_decode_cache(false));
+    report("decode every cycle:", plain, uncached, cycles);
+    let (cached, _) = time_run(&path, |_| {});
+    report("decode cache:", plain, cached, cycles);
+    #[cfg(feature = "threaded")]
+    {
+        let (threaded, _) = time_run(&path, |m| m.set_threaded(true));
+        report("threaded:", plain, threaded, cycles);
+    }
+    #[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+    {
+        let (jit, _) = time_run(&path, |m| m.set_jit(true));
+        report("jit:", plain, jit, cycles);
+    }
+}
@@ -0,0 +1,192 @@
+//! Allocation of array identifiers.
+//!
+//! Every identifier is either live, holding its array, or free. Abandoning
+//! drops the array on the spot and queues the identifier for reuse; only
+//! live identifiers can be read, amended, loaded or abandoned, so a stale
+//! identifier can never reach an array allocated after it was released.
+
+use std::sync::Arc;
+
+/// Memory use of the live arrays, array 0 included.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct ArrayStats {
+    pub live_arrays: u64,
+    pub live_words: u64,
+    pub peak_arrays: u64,
+    pub peak_words: u64,
+    pub allocations: u64,
+    pub abandons: u64,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Arrays {
+    // Arrays are shared on `Load` and only copied when one side is amended
+    slots: Vec<Option<Arc<Vec<u32>>>>,
+    // Freed identifiers, the most recent last
+    free: Vec<u32>,
+    pub(crate) stats: ArrayStats,
+}
+
+impl Arrays {
+    pub(crate) fn new(program: Vec<u32>) -> Self {
+        let mut arrays = Arrays {
+            slots: vec![None],
+            free: Vec::new(),
+            stats: ArrayStats::default(),
+        };
+        arrays.set_program(Arc::new(program));
+        arrays
+    }
+
+    /// Rebuild from the parts of a snapshot, or `None` if they are
+    /// inconsistent.
+    pub(crate) fn from_parts(
+        slots: Vec<Option<Arc<Vec<u32>>>>,
+        free: Vec<u32>,
+        stats: ArrayStats,
+    ) -> Option<Self> {
+        if slots.first().is_none_or(Option::is_none) {
+            return None;
+        }
+        let mut freed = vec![false; slots.len()];
+        for &id in &free {
+            match slots.get(id as usize) {
+                Some(None) if id != 0 && !freed[id as usize] => freed[id as usize] = true,
+                _ => return None,
+            }
+        }
+        // Every identifier is either live or free
+        if slots.iter().filter(|slot| slot.is_none()).count() != free.len() {
+            return None;
+        }
+        let live = slots.iter().flatten();
+        let stats = ArrayStats {
+            live_arrays: live.clone().count() as u64,
+            live_words: live.map(|array| array.len() as u64).sum(),
+            ..stats
+        };
+        Some(Arrays { slots, free, stats })
+    }
+
+    pub(crate) fn slots(&self) -> &[Option<Arc<Vec<u32>>>] {
+        &self.slots
+    }
+
+    pub(crate) fn free(&self) -> &[u32] {
+        &self.free
+    }
+
+    pub(crate) fn program(&self) -> &Arc<Vec<u32>> {
+        self.slots[0].as_ref().expect("Array 0 is always live")
+    }
+
+    /// Replace array 0, keeping the statistics in step.
+    pub(crate) fn set_program(&mut self, program: Arc<Vec<u32>>) {
+        self.stats.live_words += program.len() as u64;
+        match self.slots[0].replace(program) {
+            Some(old) => self.stats.live_words -= old.len() as u64,
+            None => self.stats.live_arrays += 1,
+        }
+        self.update_peaks();
+    }
+
+    pub(crate) fn get(&self, id: u32) -> Option<&Arc<Vec<u32>>> {
+        self.slots.get(id as usize).and_then(Option::as_ref)
+    }
+
+    pub(crate) fn get_mut(&mut self, id: u32) -> Option<&mut Arc<Vec<u32>>> {
+        self.slots.get_mut(id as usize).and_then(Option::as_mut)
+    }
+
+    /// Allocate a zeroed array, reusing the most recently freed identifier.
+    pub(crate) fn allocate(&mut self, len: usize) -> u32 {
+        let array = Some(Arc::new(vec![0; len]));
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.slots[id as usize] = array;
+                id
+            }
+            None => {
+                self.slots.push(array);
+                (self.slots.len() - 1) as u32
+            }
+        };
+        self.stats.allocations += 1;
+        self.stats.live_arrays += 1;
+        self.stats.live_words += len as u64;
+        self.update_peaks();
+        id
+    }
+
+    /// Release a live array other than array 0. Returns false, changing
+    /// nothing, for any other identifier.
+    pub(crate) fn abandon(&mut self, id: u32) -> bool {
+        if id == 0 {
+            return false;
+        }
+        match self.slots.get_mut(id as usize).and_then(Option::take) {
+            Some(array) => {
+                self.free.push(id);
+                self.stats.abandons += 1;
+                self.stats.live_arrays -= 1;
+                self.stats.live_words -= array.len() as u64;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take back `allocate`'s most recent call, which returned `id`, and
+    /// put the statistics back to `stats`.
+    pub(crate) fn unallocate(&mut self, id: u32, reused: bool, stats: ArrayStats) {
+        if reused {
+            self.slots[id as usize] = None;
+            self.free.push(id);
+        } else {
+            self.slots.pop();
+        }
+        self.stats = stats;
+    }
+
+    /// Take back `abandon`'s most recent call, which released `array`.
+    pub(crate) fn unabandon(&mut self, id: u32, array: Arc<Vec<u32>>, stats: ArrayStats) {
+        self.free.pop();
+        self.slots[id as usize] = Some(array);
+        self.stats = stats;
+    }
+
+    fn update_peaks(&mut self) {
+        self.stats.peak_arrays = self.stats.peak_arrays.max(self.stats.live_arrays);
+        self.stats.peak_words = self.stats.peak_words.max(self.stats.live_words);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse() {
+        let mut arrays = Arrays::new(vec![0; 4]);
+        let a = arrays.allocate(10);
+        let b = arrays.allocate(20);
+        assert_eq!((a, b), (1, 2));
+        assert!(arrays.abandon(a));
+        assert!(!arrays.abandon(a));
+        assert!(!arrays.abandon(0));
+        assert!(!arrays.abandon(9));
+        assert!(arrays.get(a).is_none());
+        assert_eq!(arrays.allocate(5), a);
+        assert_eq!(
+            arrays.stats,
+            ArrayStats {
+                live_arrays: 3,
+                live_words: 29,
+                peak_arrays: 3,
+                peak_words: 34,
+                allocations: 3,
+                abandons: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,567 @@
+//! Assembling text into a scroll.
+//!
+//! Each line holds any number of `label:` definitions followed by an
+//! instruction or a directive, and anything after a `;` is a comment.
+//! Instructions use the mnemonics of `MNEMONICS` with the operands
+//! `Instruction`'s `Display` shows, so the output of `disasm` assembles
+//! back to the scroll it listed:
+//!
+//! ```text
+//! start:  ortho r1, 'A'     ; r1 <- 65
+//!         out r1
+//!         ortho r2, start
+//!         load r0, r2       ; jump to start
+//! ```
+//!
+//! Values are sums and differences of numbers (decimal or `0x` hex),
+//! character literals and symbols, wrapping to 32 bits, so `-1` is
+//! `0xffffffff`. An `ortho` takes the low 25 bits of a value from -2^24 to
+//! -1. The directives are:
+//!
+//! - `.word V, ...` places each value as a platter
+//! - `.string "TEXT", ...` places each byte of the text as a platter
+//! - `.space N` places `N` zero platters, at most `MAX_SPACE`
+//! - `.const NAME, V` names a value; it may only use symbols defined above
+//!
+//! Labels may be used anywhere in a value, including before they are
+//! defined, except by `.const` and `.space`.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{Instruction, OrthoPointers, Pointers, MNEMONICS};
+
+/// The most platters one `.space` places, 64 MiB worth.
+pub const MAX_SPACE: u32 = 1 << 24;
+
+/// What was wrong with a line.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ErrorKind {
+    UnknownMnemonic(String),
+    Operands {
+        mnemonic: String,
+        expected: usize,
+        found: usize,
+    },
+    NotARegister(String),
+    BadValue(String),
+    BadName(String),
+    Undefined(String),
+    Redefined(String),
+    ImmediateTooLarge(u32),
+    SpaceTooLarge(u32),
+}
+
+/// Why a source could not be assembled, and the line (from 1) at fault.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ErrorKind::*;
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic or directive `{}`", mnemonic),
+            Operands {
+                mnemonic,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{}` takes {} operands, found {}",
+                mnemonic, expected, found
+            ),
+            NotARegister(s) => write!(f, "`{}` is not a register", s),
+            BadValue(s) => write!(f, "`{}` is not a value", s),
+            BadName(s) => write!(f, "`{}` is not a valid name", s),
+            Undefined(name) => write!(f, "`{}` is not defined", name),
+            Redefined(name) => write!(f, "`{}` is already defined", name),
+            ImmediateTooLarge(value) => {
+                write!(f, "{} does not fit in the 25 bits of an ortho", value)
+            }
+            SpaceTooLarge(len) => {
+                write!(f, "`.space {}` is over the limit of {}", len, MAX_SPACE)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Split `s` at each `separator` that is not inside a string or character
+/// literal.
+fn split_unquoted(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match quote {
+            _ if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == separator => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            None => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// The bytes of a literal's body, with escapes (`\n`, `\t`, `\r`, `\0`,
+/// `\\`, `\'`, `\"` and `\xNN`) replaced.
+fn unescape(body: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        bytes.push(match chars.next()? {
+            'n' => b'\n',
+            't' => b'\t',
+            'r' => b'\r',
+            '0' => 0,
+            '\\' => b'\\',
+            '\'' => b'\'',
+            '"' => b'"',
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 {
+                    return None;
+                }
+                u8::from_str_radix(&hex, 16).ok()?
+            }
+            _ => return None,
+        });
+    }
+    Some(bytes)
+}
+
+/// The body of a literal quoted by `quote`.
+fn quoted(s: &str, quote: char) -> Option<&str> {
+    s.strip_prefix(quote)?.strip_suffix(quote)
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn register(s: &str) -> Result<usize, ErrorKind> {
+    match s.strip_prefix('r').map(str::parse) {
+        Some(Ok(r)) if r < 8 => Ok(r),
+        _ => Err(ErrorKind::NotARegister(s.into())),
+    }
+}
+
+/// One term of a value: a number, a character literal or a symbol.
+fn term(s: &str, symbols: &HashMap<String, u32>) -> Result<u32, ErrorKind> {
+    let bad = || ErrorKind::BadValue(s.into());
+    if s.starts_with('\'') {
+        let bytes = quoted(s, '\'').and_then(unescape).ok_or_else(bad)?;
+        return match bytes.as_slice() {
+            [byte] => Ok(u32::from(*byte)),
+            _ => Err(bad()),
+        };
+    }
+    if s.starts_with(|c: char| c.is_ascii_digit()) {
+        let parsed = match s.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse(),
+        };
+        return parsed.map_err(|_| bad());
+    }
+    if !is_name(s) {
+        return Err(bad());
+    }
+    symbols
+        .get(s)
+        .cloned()
+        .ok_or_else(|| ErrorKind::Undefined(s.into()))
+}
+
+/// Evaluate a sum of terms, wrapping as the machine's `add` does. A sign
+/// with no term before it applies to the term after it.
+fn value(s: &str, symbols: &HashMap<String, u32>) -> Result<u32, ErrorKind> {
+    let mut total = 0u32;
+    let mut negate = false;
+    let mut start = 0;
+    let mut quote = false;
+    let mut escaped = false;
+    let s = s.trim();
+    for (i, c) in s.char_indices().chain(Some((s.len(), '+'))) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quote => escaped = true,
+            '\'' => quote = !quote,
+            '+' | '-' if !quote && i < s.len() && s[start..i].trim().is_empty() => {
+                negate ^= c == '-';
+                start = i + 1;
+            }
+            '+' | '-' if !quote => {
+                let term = term(s[start..i].trim(), symbols)?;
+                total = if negate {
+                    total.wrapping_sub(term)
+                } else {
+                    total.wrapping_add(term)
+                };
+                negate = c == '-';
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(total)
+}
+
+/// A line with its labels and comment removed.
+struct Statement<'a> {
+    line: usize,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+impl<'a> Statement<'a> {
+    fn parse(line: usize, text: &'a str) -> (Vec<&'a str>, Option<Self>) {
+        let mut rest = split_unquoted(text, ';')[0].trim();
+        let mut labels = Vec::new();
+        while let Some((label, after)) = rest.split_once(':') {
+            if !is_name(label.trim()) {
+                break;
+            }
+            labels.push(label.trim());
+            rest = after.trim();
+        }
+        if rest.is_empty() {
+            return (labels, None);
+        }
+        let (mnemonic, operands) = match rest.split_once(char::is_whitespace) {
+            Some((mnemonic, operands)) => (
+                mnemonic,
+                split_unquoted(operands, ',')
+                    .into_iter()
+                    .map(str::trim)
+                    .collect(),
+            ),
+            None => (rest, Vec::new()),
+        };
+        let statement = Statement {
+            line,
+            mnemonic,
+            operands,
+        };
+        (labels, Some(statement))
+    }
+
+    fn expect(&self, expected: usize) -> Result<(), ErrorKind> {
+        match self.operands.len() {
+            found if found == expected => Ok(()),
+            found => Err(ErrorKind::Operands {
+                mnemonic: self.mnemonic.into(),
+                expected,
+                found,
+            }),
+        }
+    }
+
+    /// The bytes of every `.string` operand.
+    fn string(&self) -> Result<Vec<u8>, ErrorKind> {
+        let mut bytes = Vec::new();
+        for operand in &self.operands {
+            let text = quoted(operand, '"')
+                .and_then(unescape)
+                .ok_or_else(|| ErrorKind::BadValue((*operand).into()))?;
+            bytes.extend(text);
+        }
+        Ok(bytes)
+    }
+
+    /// How many platters the statement places, defining any constant.
+    fn size(&self, symbols: &mut HashMap<String, u32>) -> Result<usize, ErrorKind> {
+        Ok(match self.mnemonic {
+            ".word" => self.operands.len(),
+            ".string" => self.string()?.len(),
+            ".space" => {
+                self.expect(1)?;
+                match value(self.operands[0], symbols)? {
+                    len if len > MAX_SPACE => return Err(ErrorKind::SpaceTooLarge(len)),
+                    len => len as usize,
+                }
+            }
+            ".const" => {
+                self.expect(2)?;
+                let name = self.operands[0];
+                if !is_name(name) {
+                    return Err(ErrorKind::BadName(name.into()));
+                }
+                let value = value(self.operands[1], symbols)?;
+                if symbols.insert(name.into(), value).is_some() {
+                    return Err(ErrorKind::Redefined(name.into()));
+                }
+                0
+            }
+            mnemonic if MNEMONICS.contains(&mnemonic) => 1,
+            mnemonic => return Err(ErrorKind::UnknownMnemonic(mnemonic.into())),
+        })
+    }
+
+    fn emit(&self, symbols: &HashMap<String, u32>, out: &mut Vec<u32>) -> Result<(), ErrorKind> {
+        match self.mnemonic {
+            ".word" => {
+                for operand in &self.operands {
+                    out.push(value(operand, symbols)?);
+                }
+            }
+            ".string" => out.extend(self.string()?.into_iter().map(u32::from)),
+            ".space" => {
+                let len = value(self.operands[0], symbols)? as usize;
+                out.resize(out.len() + len, 0);
+            }
+            ".const" => {}
+            _ => out.push(self.instruction(symbols)?.encode()),
+        }
+        Ok(())
+    }
+
+    fn instruction(&self, symbols: &HashMap<String, u32>) -> Result<Instruction, ErrorKind> {
+        let opcode = MNEMONICS.iter().position(|&m| m == self.mnemonic).unwrap();
+        let r = |i: usize| register(self.operands[i]);
+        let pointers = match opcode {
+            0..=6 => {
+                self.expect(3)?;
+                Pointers {
+                    a: r(0)?,
+                    b: r(1)?,
+                    c: r(2)?,
+                }
+            }
+            7 => {
+                self.expect(0)?;
+                Pointers { a: 0, b: 0, c: 0 }
+            }
+            8 | 12 => {
+                self.expect(2)?;
+                Pointers {
+                    a: 0,
+                    b: r(0)?,
+                    c: r(1)?,
+                }
+            }
+            9..=11 => {
+                self.expect(1)?;
+                Pointers {
+                    a: 0,
+                    b: 0,
+                    c: r(0)?,
+                }
+            }
+            _ => {
+                self.expect(2)?;
+                let mut value = value(self.operands[1], symbols)?;
+                if value >= 0u32.wrapping_sub(1 << 24) {
+                    value &= (1 << 25) - 1;
+                }
+                if value >= 1 << 25 {
+                    return Err(ErrorKind::ImmediateTooLarge(value));
+                }
+                return Ok(Instruction::Ortho(OrthoPointers { a: r(0)?, value }));
+            }
+        };
+        Ok(match opcode {
+            0 => Instruction::Move(pointers),
+            1 => Instruction::Index(pointers),
+            2 => Instruction::Amend(pointers),
+            3 => Instruction::Add(pointers),
+            4 => Instruction::Mul(pointers),
+            5 => Instruction::Div(pointers),
+            6 => Instruction::Nand(pointers),
+            7 => Instruction::Halt(pointers),
+            8 => Instruction::Allocate(pointers),
+            9 => Instruction::Abandon(pointers),
+            10 => Instruction::Out(pointers),
+            11 => Instruction::In(pointers),
+            _ => Instruction::Load(pointers),
+        })
+    }
+}
+
+/// Assemble `source` into the platters of a scroll.
+pub fn assemble(source: &str) -> Result<Vec<u32>, AsmError> {
+    let mut symbols = HashMap::new();
+    let mut statements = Vec::new();
+    let mut offset = 0;
+    // Place everything first so labels can be used before their definition
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let error = |kind| AsmError { line, kind };
+        let (labels, statement) = Statement::parse(line, text);
+        for label in labels {
+            if symbols.insert(label.into(), offset as u32).is_some() {
+                return Err(error(ErrorKind::Redefined(label.into())));
+            }
+        }
+        if let Some(statement) = statement {
+            offset += statement.size(&mut symbols).map_err(error)?;
+            statements.push(statement);
+        }
+    }
+    let mut program = Vec::with_capacity(offset);
+    for statement in &statements {
+        statement
+            .emit(&symbols, &mut program)
+            .map_err(|kind| AsmError {
+                line: statement.line,
+                kind,
+            })?;
+    }
+    Ok(program)
+}
+
+/// A machine running `source`, reading and writing through `io`.
+#[cfg(test)]
+pub(crate) fn boot(source: &str, io: impl crate::io::UmIo + 'static) -> crate::Machine {
+    boot_words(&assemble(source).unwrap(), io)
+}
+
+/// A machine running `program`, reading and writing through `io`.
+#[cfg(test)]
+pub(crate) fn boot_words(program: &[u32], io: impl crate::io::UmIo + 'static) -> crate::Machine {
+    let mut scroll = Vec::new();
+    crate::write_scroll(&mut scroll, program).unwrap();
+    crate::Machine::new(io, &mut scroll.as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::disasm::{disassemble, Options};
+    use crate::io::QueueIo;
+    use crate::{RunLimit, RunOutcome};
+
+    #[test]
+    fn test_assemble() {
+        let source = r#"
+            .const NEWLINE, 10
+            start:  ortho r1, message     ; r1 points at the text
+                    ortho r2, 1
+                    ortho r3, end - message
+                    index r4, r0, r1
+                    out r4
+                    add r1, r1, r2
+            done:   ; a label on a line of its own
+                    halt
+            message: .string "hi", "\x21"
+                    .word NEWLINE
+            end:    .space 2
+        "#;
+        let program = assemble(source).unwrap();
+        assert_eq!(program[0], 0xD200_0007);
+        assert_eq!(program[2], 0xD600_0004);
+        assert_eq!(program[3], 0x1000_0101);
+        assert_eq!(program[4], 0xA000_0004);
+        assert_eq!(program[6], 0x7000_0000);
+        assert_eq!(&program[7..], &[104, 105, 33, 10, 0, 0]);
+    }
+
+    #[test]
+    fn test_runs() {
+        let source = "
+                    ortho r1, 'o'
+                    ortho r2, 'k'
+                    out r1
+                    out r2
+                    halt
+        ";
+        let io = QueueIo::new();
+        let mut machine = boot(source, io.clone());
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Halted);
+        assert_eq!(io.drain_output(), b"ok");
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source: &str| assemble(source).unwrap_err();
+        assert_eq!(
+            error("halt\nfoo r1"),
+            AsmError {
+                line: 2,
+                kind: ErrorKind::UnknownMnemonic("foo".into())
+            }
+        );
+        assert_eq!(
+            error("add r1, r2").kind,
+            ErrorKind::Operands {
+                mnemonic: "add".into(),
+                expected: 3,
+                found: 2
+            }
+        );
+        assert_eq!(error("out r8").kind, ErrorKind::NotARegister("r8".into()));
+        assert_eq!(
+            error("ortho r0, missing").kind,
+            ErrorKind::Undefined("missing".into())
+        );
+        assert_eq!(error("a: a: halt").kind, ErrorKind::Redefined("a".into()));
+        assert_eq!(
+            error("ortho r0, 0x2000000").kind,
+            ErrorKind::ImmediateTooLarge(1 << 25)
+        );
+        assert_eq!(error(".word 'ab'").kind, ErrorKind::BadValue("'ab'".into()));
+        assert_eq!(
+            error("halt\n.space 0xffffffff"),
+            AsmError {
+                line: 2,
+                kind: ErrorKind::SpaceTooLarge(0xFFFF_FFFF)
+            }
+        );
+        assert_eq!(error(".word 1 +").kind, ErrorKind::BadValue("".into()));
+    }
+
+    #[test]
+    fn test_negative() {
+        let program = assemble(".word -1, 2 - -3, -'a' + 'b', +4\northo r1, -1").unwrap();
+        assert_eq!(program, [0xFFFF_FFFF, 5, 1, 4, 0xD3FF_FFFF]);
+        assert_eq!(
+            assemble("ortho r0, -0x1000001").unwrap_err().kind,
+            ErrorKind::ImmediateTooLarge(0xFEFF_FFFF)
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Every opcode, with and without stray bits, and data
+        let mut state = 0x2545_F491u32;
+        let mut program: Vec<u32> = (0..16).map(|op| op << 28 | 0x1FF).collect();
+        program.extend((0..2000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        }));
+        program.extend(&[0xD000_0027, 0xD000_005C, 0xD000_003B, 0xD000_0022]);
+        let options = Options {
+            hex: true,
+            mark_invalid: true,
+            ..Options::default()
+        };
+        let mut listing = Vec::new();
+        disassemble(&program, &options, &mut listing).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        assert_eq!(assemble(&listing), Ok(program));
+    }
+}
@@ -5,7 +5,8 @@ use std::io::Write;
 use std::sync::mpsc::channel;
 use std::thread;
 
-use cbv::{spin, Machine};
+use cbv::io::ChannelIo;
+use cbv::{Machine, RunLimit, RunOutcome};
 use crypto::{digest::Digest, sha1::Sha1};
 
 static CODEX_URL: &str = "http://www.boundvariable.org/codex.umz";
@@ -41,25 +42,27 @@ fn main() {
     let mut export_file = File::create(&file_name).unwrap();
     let (client_sender, client_receiver) = channel();
     let (machine_sender, machine_receiver) = channel();
-    let mut machine = Machine::new(machine_receiver, client_sender, &mut codex().as_slice());
+    let io = ChannelIo::new(machine_receiver, client_sender);
+    let mut machine = Machine::new(io, &mut codex().as_slice());
     for byte in CODEX_DECRYPTION_KEY
         .bytes()
         .chain(vec![10u8, 112u8, 10u8].into_iter())
     {
-        machine_sender.send(u32::from(byte)).unwrap();
+        machine_sender.send(byte).unwrap();
     }
+    drop(machine_sender);
     let handle = thread::spawn(move || {
         let mut buf = Vec::new();
         let mut export = false;
         while let Ok(i) = client_receiver.recv() {
-            buf.push(i as u8);
+            buf.push(i);
             if export {
                 continue;
             }
             if buf.len() > SIG_EXPORT.len() {
                 buf.remove(0);
             }
-            print!("{}", i as u8 as char);
+            print!("{}", char::from(i));
             if buf == SIG_EXPORT {
                 buf.truncate(0);
                 export = true;
@@ -69,8 +72,9 @@ fn main() {
         export_file.write_all(buf.as_slice()).unwrap();
     });
 
-    while let Some(m) = spin(machine) {
-        machine = m
+    if let RunOutcome::Fault(fault) = machine.run(RunLimit::default()) {
+        panic!("Codex faulted: {}", fault);
     }
+    drop(machine);
     handle.join().unwrap();
 }
\ No newline at end of file
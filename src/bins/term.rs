@@ -1,49 +1,394 @@
-use cbv::{spin, Machine};
-
+use cbv::coverage::Coverage;
+use cbv::io::{push_front, Input, QueueIo, UmIo};
+use cbv::profile::Profiler;
+use cbv::record::{replay, Recording};
+use cbv::trace::{Filter, Format, TraceWriter, Tracer};
+use cbv::{Machine, RunLimit, RunOutcome, MNEMONICS};
 
+use std::collections::VecDeque;
 use std::env;
 
 use std::fs::File;
-use std::io::{stdin, Write};
-use std::io::{BufReader, Read};
+use std::io::{self, stdin, Write};
+use std::io::{BufReader, BufWriter, Read};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
-use std::sync::mpsc::channel;
-
+use std::time::{Duration, Instant};
 
 type Tty = BufReader<File>;
 
+// Ctrl-] on the tty, followed by a command and return
+const ESCAPE: u8 = 0x1D;
+const DEFAULT_SNAPSHOT: &str = "state.umsnap";
+// Offsets listed in a profile report
+const PROFILE_TOP: usize = 20;
+// How often a busy machine looks for escaped commands
+const SLICE: Duration = Duration::from_millis(50);
+
 fn open_tty() -> Tty {
     let f = File::open("/dev/tty").expect("Could Not Open TTY");
     BufReader::new(f)
 }
 
+enum Event {
+    Byte(u8),
+    Command(String),
+}
+
+/// Input from the tty thread, with escaped commands split out so the
+/// machine can be paused to act on them.
+struct Events {
+    receiver: Receiver<Event>,
+    input: VecDeque<u8>,
+    commands: VecDeque<String>,
+    closed: bool,
+}
+
+impl Events {
+    fn accept(&mut self, event: Event) {
+        match event {
+            Event::Byte(b) => self.input.push_back(b),
+            Event::Command(command) => self.commands.push_back(command),
+        }
+    }
+
+    fn poll(&mut self) {
+        while let Ok(event) = self.receiver.try_recv() {
+            self.accept(event);
+        }
+    }
+}
+
+struct TtyIo {
+    events: Arc<Mutex<Events>>,
+    output: Sender<u8>,
+}
+
+impl UmIo for TtyIo {
+    fn read_byte(&mut self) -> Input {
+        let mut events = self.events.lock().unwrap();
+        loop {
+            if let Some(b) = events.input.pop_front() {
+                return Input::Byte(b);
+            }
+            // Park the machine on `In` so the command can be carried out
+            if !events.commands.is_empty() {
+                return Input::WouldBlock;
+            }
+            if events.closed {
+                return Input::Eof;
+            }
+            match events.receiver.recv() {
+                Ok(event) => events.accept(event),
+                Err(_) => events.closed = true,
+            }
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output
+            .send(byte)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Output channel closed"))
+    }
+
+    fn pending_input(&mut self) -> Vec<u8> {
+        let mut events = self.events.lock().unwrap();
+        events.poll();
+        events.input.iter().cloned().collect()
+    }
+
+    fn unread(&mut self, input: &[u8]) {
+        push_front(&mut self.events.lock().unwrap().input, input);
+    }
+
+    fn restore(&mut self, pending_input: &[u8], buffered_output: &[u8]) {
+        self.unread(pending_input);
+        for &b in buffered_output {
+            let _ = self.output.send(b);
+        }
+    }
+}
+
+struct Options {
+    save_on_exit: Option<String>,
+    resume: Option<String>,
+    record: Option<String>,
+    replay: Option<String>,
+    trace: Option<String>,
+    trace_format: Format,
+    trace_filter: Filter,
+    profile: Option<String>,
+    coverage: Option<String>,
+    instructions: Vec<String>,
+}
+
+fn bad_option(option: &str, value: &str) -> ! {
+    eprintln!("Invalid {} {:?}", option, value);
+    std::process::exit(2);
+}
+
+fn number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// `START-END`, `START-` or a single number, inclusive.
+fn span(option: &str, s: &str) -> RangeInclusive<u64> {
+    let bounds = match s.split_once('-') {
+        Some((start, "")) => number(start).map(|start| start..=u64::MAX),
+        Some((start, end)) => number(start).zip(number(end)).map(|(s, e)| s..=e),
+        None => number(s).map(|n| n..=n),
+    };
+    bounds.unwrap_or_else(|| bad_option(option, s))
+}
+
+/// Comma-separated mnemonics or opcode numbers.
+fn opcodes(s: &str) -> Vec<u32> {
+    s.split(',')
+        .map(|op| match MNEMONICS.iter().position(|&m| m == op) {
+            Some(opcode) => opcode as u32,
+            None => match number(op) {
+                Some(opcode) if opcode < 14 => opcode as u32,
+                _ => bad_option("--trace-ops", op),
+            },
+        })
+        .collect()
+}
+
+fn options() -> Options {
+    let mut options = Options {
+        save_on_exit: None,
+        resume: None,
+        record: None,
+        replay: None,
+        trace: None,
+        trace_format: Format::Text,
+        trace_filter: Filter::default(),
+        profile: None,
+        coverage: None,
+        instructions: Vec::new(),
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--save-on-exit" => options.save_on_exit = args.next(),
+            "--resume" => options.resume = args.next(),
+            "--record" => options.record = args.next(),
+            "--replay" => options.replay = args.next(),
+            "--trace" => options.trace = args.next(),
+            "--profile" => options.profile = args.next(),
+            "--coverage" => options.coverage = args.next(),
+            "--trace-format" => {
+                options.trace_format = match args.next().as_deref() {
+                    Some("text") => Format::Text,
+                    Some("binary") => Format::Binary,
+                    other => bad_option("--trace-format", other.unwrap_or("")),
+                }
+            }
+            "--trace-fingers" => {
+                let value = args.next().unwrap_or_default();
+                let (start, end) = span("--trace-fingers", &value).into_inner();
+                options.trace_filter.fingers = Some(start as usize..=end as usize);
+            }
+            "--trace-ops" => {
+                let value = args.next().unwrap_or_default();
+                options.trace_filter.opcodes = Some(opcodes(&value));
+            }
+            "--trace-cycles" => {
+                let value = args.next().unwrap_or_default();
+                options.trace_filter.cycles = Some(span("--trace-cycles", &value));
+            }
+            _ => options.instructions.push(arg),
+        }
+    }
+    options
+}
+
+fn save(machine: &mut Machine, path: &str) {
+    let result = File::create(path).and_then(|mut file| machine.save(&mut file));
+    match result {
+        Ok(()) => eprintln!("\nSaved snapshot to {}", path),
+        Err(e) => eprintln!("\nCould not save snapshot to {}: {}", path, e),
+    }
+}
+
+fn write_profile(profiler: &Profiler, path: &str) {
+    let result = File::create(path).and_then(|mut file| profiler.report(PROFILE_TOP, &mut file));
+    match result {
+        Ok(()) => eprintln!("\nWrote profile to {}", path),
+        Err(e) => eprintln!("\nCould not write profile to {}: {}", path, e),
+    }
+}
+
+/// Tracers kept by term as well as the machine, to report on at exit.
+struct Tools {
+    profiler: Option<Arc<Mutex<Profiler>>>,
+    coverage: Option<Arc<Mutex<Coverage>>>,
+}
+
+impl Tools {
+    fn new(options: &Options) -> Self {
+        Tools {
+            profiler: options.profile.as_ref().map(|_| Arc::default()),
+            coverage: options.coverage.as_ref().map(|_| Arc::default()),
+        }
+    }
+
+    fn write(&self, options: &Options) {
+        if let (Some(path), Some(profiler)) = (&options.profile, &self.profiler) {
+            write_profile(&profiler.lock().unwrap(), path);
+        }
+        if let (Some(dir), Some(coverage)) = (&options.coverage, &self.coverage) {
+            match coverage.lock().unwrap().save(Path::new(dir)) {
+                Ok(()) => eprintln!("\nWrote coverage to {}", dir),
+                Err(e) => eprintln!("\nCould not write coverage to {}: {}", dir, e),
+            }
+        }
+    }
+}
+
+fn exit(machine: &mut Machine, options: &Options, tools: &Tools, code: i32) -> ! {
+    // Dropping the tracer flushes the trace
+    machine.set_tracer(None);
+    tools.write(options);
+    if let Some(path) = &options.save_on_exit {
+        save(machine, path);
+    }
+    if let (Some(path), Some(recording)) = (&options.record, machine.stop_recording()) {
+        let result = File::create(path).and_then(|mut file| recording.write(&mut file));
+        if let Err(e) = result {
+            eprintln!("\nCould not save recording to {}: {}", path, e);
+        }
+    }
+    std::process::exit(code);
+}
+
+fn boot(io: impl UmIo + 'static, options: &Options, tools: &Tools) -> Machine {
+    let mut machine = match &options.resume {
+        Some(path) => {
+            let mut file = File::open(path).expect("Could not open snapshot");
+            Machine::restore(io, &mut file).unwrap_or_else(|e| {
+                eprintln!("Could not resume from {}: {}", path, e);
+                std::process::exit(1);
+            })
+        }
+        None => Machine::new(io, &mut stdin()),
+    };
+    let mut tracers: Vec<Box<dyn Tracer>> = Vec::new();
+    if let Some(path) = &options.trace {
+        let file = File::create(path).expect("Could not create trace");
+        let filter = options.trace_filter.clone();
+        let writer = TraceWriter::new(BufWriter::new(file), options.trace_format, filter)
+            .expect("Could not write trace");
+        tracers.push(Box::new(writer));
+    }
+    if let Some(profiler) = &tools.profiler {
+        tracers.push(Box::new(Arc::clone(profiler)));
+    }
+    if let Some(coverage) = &tools.coverage {
+        tracers.push(Box::new(Arc::clone(coverage)));
+    }
+    if !tracers.is_empty() {
+        machine.set_tracer(Some(Box::new(tracers)));
+    }
+    machine
+}
+
+/// Re-run a recording headless and check it still produces the same output.
+fn replay_recording(path: &str, options: &Options) -> ! {
+    let mut file = File::open(path).expect("Could not open recording");
+    let recording = Recording::read(&mut file).unwrap_or_else(|e| {
+        eprintln!("Could not read recording {}: {}", path, e);
+        std::process::exit(1);
+    });
+    let io = QueueIo::new();
+    let tools = Tools::new(options);
+    let mut machine = boot(io.clone(), options, &tools);
+    let result = replay(&mut machine, &io, &recording);
+    machine.set_tracer(None);
+    tools.write(options);
+    match result {
+        Ok(()) => {
+            eprintln!(
+                "Replayed {} cycles and {} input bytes, output matches",
+                recording.end_cycle - recording.start_cycle,
+                recording.input.len()
+            );
+            std::process::exit(0);
+        }
+        Err(divergence) => {
+            eprintln!("Replay diverged: {}", divergence);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() {
+    let options = options();
+    if let Some(path) = &options.replay {
+        replay_recording(path, &options);
+    }
     let mut log = File::create("session.log").expect("Could not open log");
     let (client_sender, client_receiver) = channel();
     let (machine_sender, machine_receiver) = channel();
-    let mut machine = Machine::new(machine_receiver, client_sender, &mut stdin());
-    for instruction in env::args().skip(1) {
-        log.write_all(instruction.bytes().collect::<Vec<u8>>().as_slice()).unwrap();
+    let events = Arc::new(Mutex::new(Events {
+        receiver: machine_receiver,
+        input: VecDeque::new(),
+        commands: VecDeque::new(),
+        closed: false,
+    }));
+    let io = TtyIo {
+        events: Arc::clone(&events),
+        output: client_sender,
+    };
+    let tools = Tools::new(&options);
+    let mut machine = boot(io, &options, &tools);
+    if options.record.is_some() {
+        machine.start_recording(options.instructions.clone());
+    }
+    for instruction in &options.instructions {
+        log.write_all(instruction.bytes().collect::<Vec<u8>>().as_slice())
+            .unwrap();
         for byte in instruction.bytes() {
             machine_sender
-                .send(u32::from(byte))
+                .send(Event::Byte(byte))
                 .expect("Machine channel closed during initialization");
         }
         machine_sender
-            .send(10u32)
+            .send(Event::Byte(10u8))
             .expect("Machine channel closed during initialization");
         log.write_all(&[10u8]).unwrap();
     }
 
     thread::spawn(move || loop {
+        let mut command: Option<Vec<u8>> = None;
         for b in open_tty().bytes() {
             let b = b.expect("Read error from stdin");
-            log.write_all(&[b]).unwrap();
-            if machine_sender
-                .send(u32::from(b))
-                .is_err()
-            {
+            let event = match (command.as_mut(), b) {
+                (None, ESCAPE) => {
+                    command = Some(Vec::new());
+                    continue;
+                }
+                (None, _) => {
+                    log.write_all(&[b]).unwrap();
+                    Event::Byte(b)
+                }
+                (Some(line), b'\n') => {
+                    let line = String::from_utf8_lossy(line).trim().to_owned();
+                    command = None;
+                    Event::Command(line)
+                }
+                (Some(line), _) => {
+                    line.push(b);
+                    continue;
+                }
+            };
+            if machine_sender.send(event).is_err() {
                 break;
             };
         }
@@ -52,11 +397,42 @@ fn main() {
 
     thread::spawn(move || {
         while let Ok(i) = client_receiver.recv() {
-            print!("{}", i as u8 as char)
+            print!("{}", char::from(i))
         }
     });
 
-    while let Some(m) = spin(machine) {
-        machine = m
+    loop {
+        match machine.run(RunLimit::until(Instant::now() + SLICE)) {
+            RunOutcome::Halted => exit(&mut machine, &options, &tools, 0),
+            RunOutcome::Fault(fault) => {
+                eprintln!("\nMachine fault: {}", fault);
+                exit(&mut machine, &options, &tools, 1);
+            }
+            _ => {}
+        }
+        events.lock().unwrap().poll();
+        let commands: Vec<String> = events.lock().unwrap().commands.drain(..).collect();
+        for command in commands {
+            let mut words = command.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("save"), path) => {
+                    let path = path
+                        .or(options.save_on_exit.as_deref())
+                        .unwrap_or(DEFAULT_SNAPSHOT);
+                    save(&mut machine, path);
+                }
+                (Some("profile"), path) => match (&tools.profiler, &options.profile) {
+                    (Some(profiler), Some(default)) => {
+                        write_profile(&profiler.lock().unwrap(), path.unwrap_or(default))
+                    }
+                    _ => eprintln!("\nStart term with --profile PATH to profile"),
+                },
+                (Some("quit"), None) => exit(&mut machine, &options, &tools, 0),
+                _ => eprintln!(
+                    "\nUnknown command {:?}, try `save [path]`, `profile [path]` or `quit`",
+                    command
+                ),
+            }
+        }
     }
 }
@@ -0,0 +1,50 @@
+use cbv::asm::assemble;
+use cbv::write_scroll;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{stdin, stdout, Read};
+use std::process;
+
+const USAGE: &str = "Usage: umasm [SOURCE] [-o SCROLL]";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let mut path = None;
+    let mut output = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            "-h" | "--help" => usage(),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let source = match &path {
+        Some(path) => fs::read_to_string(path).expect("Could not read source"),
+        None => {
+            let mut source = String::new();
+            stdin()
+                .read_to_string(&mut source)
+                .expect("Could not read source");
+            source
+        }
+    };
+    let program = assemble(&source).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path.as_deref().unwrap_or("<stdin>"), e);
+        process::exit(1);
+    });
+    let result = match output {
+        Some(output) => File::create(output).and_then(|mut file| write_scroll(&mut file, &program)),
+        None => write_scroll(&mut stdout(), &program),
+    };
+    if let Err(e) = result {
+        eprintln!("Could not write scroll: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,384 @@
+use cbv::breakpoint::{Breakpoint, Comparison, Predicate, Trigger};
+use cbv::history::HistoryConfig;
+use cbv::io::QueueIo;
+use cbv::{Instruction, Machine, RunLimit, RunOutcome, MNEMONICS};
+
+use std::convert::TryFrom;
+use std::env;
+use std::fs::File;
+use std::io::{stdin, stdout, BufRead, Write};
+use std::ops::RangeInclusive;
+use std::process;
+
+const USAGE: &str = "Usage: umdb SCROLL [--input FILE]";
+
+const HELP: &str = "\
+step [N]              execute N instructions (default 1)
+continue              run until a breakpoint, halt, fault or input starvation
+back [N]              undo N instructions (default 1)
+reverse-continue ID OFFSET
+                      go back to the last amend of array ID at OFFSET
+rewind CYCLE          go back to before CYCLE executed
+break FINGER          break when the finger reaches FINGER
+break-op OPCODE       break before any instruction with OPCODE (name or number)
+watch ID OFFSET[-END] break before any amend of array ID in the offsets
+rwatch ID OFFSET[-END]
+                      break before any index of array ID in the offsets
+break-alloc           break before any alloc
+break-abandon [ID]    break before abandoning array ID, or any array
+break-out BYTE        break before BYTE is output
+                      Any break or watch may end with `if rN OP VALUE`, OP
+                      being one of == != < <= > >=
+info                  list breakpoints
+delete N              remove breakpoint N
+regs                  print the finger and registers
+set rN VALUE          set a register
+set finger VALUE      move the finger
+poke ID OFFSET VALUE  write a word of array ID
+arrays                list live arrays and their lengths
+dump ID START [COUNT] print COUNT words of array ID from START (default 16)
+dis [N]               disassemble N instructions around the finger (default 10)
+input TEXT            queue TEXT and a newline as input
+eof                   close the input
+quit
+Numbers may be decimal or 0x-prefixed hex. An empty line repeats the last command.";
+
+struct Debugger {
+    machine: Machine,
+    io: QueueIo,
+}
+
+fn number(s: &str) -> Result<u32, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("Not a number: {}", s))
+}
+
+fn register(s: &str) -> Result<usize, String> {
+    match s.trim_start_matches('r').parse() {
+        Ok(r) if r < 8 => Ok(r),
+        _ => Err(format!("Not a register: {}", s)),
+    }
+}
+
+fn opcode(s: &str) -> Result<u32, String> {
+    match MNEMONICS.iter().position(|&m| m == s) {
+        Some(opcode) => Ok(opcode as u32),
+        None => match number(s) {
+            Ok(opcode) if opcode < 14 => Ok(opcode),
+            _ => Err(format!("Not an opcode: {}", s)),
+        },
+    }
+}
+
+fn offsets(s: &str) -> Result<RangeInclusive<u32>, String> {
+    match s.split_once('-') {
+        Some((start, end)) => Ok(number(start)?..=number(end)?),
+        None => number(s).map(|offset| offset..=offset),
+    }
+}
+
+/// Parse a condition such as `r3 >= 10` or `r3>=10`.
+fn predicate(s: &str) -> Result<Predicate, String> {
+    const COMPARISONS: [(&str, Comparison); 6] = [
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ];
+    let (symbol, comparison) = COMPARISONS
+        .iter()
+        .find(|(symbol, _)| s.contains(symbol))
+        .ok_or_else(|| format!("Not a condition: {}", s))?;
+    let (register_name, value) = s.split_once(symbol).unwrap();
+    Predicate::new(
+        register(register_name.trim())?,
+        *comparison,
+        number(value.trim())?,
+    )
+    .map_err(|e| e.to_string())
+}
+
+impl Debugger {
+    fn word(&self, offset: usize) -> Option<u32> {
+        self.machine
+            .array(0)
+            .and_then(|program| program.get(offset).cloned())
+    }
+
+    fn line(&self, offset: usize) -> String {
+        let marker = if offset == self.machine.finger() {
+            "=>"
+        } else {
+            "  "
+        };
+        match self.word(offset) {
+            Some(word) => match Instruction::decode(word) {
+                Some(instruction) => {
+                    format!("{} {:#010x}: {:08x}  {}", marker, offset, word, instruction)
+                }
+                None => format!("{} {:#010x}: {:08x}  (invalid)", marker, offset, word),
+            },
+            None => format!("{} {:#010x}: (outside array 0)", marker, offset),
+        }
+    }
+
+    /// Execute up to `count` instructions, stopping early at breakpoints
+    /// other than one on the instruction we start from.
+    fn run(&mut self, count: Option<u64>) {
+        let limit = match count {
+            Some(count) => RunLimit::cycles(count),
+            None => RunLimit::default(),
+        };
+        match self.machine.run(limit) {
+            RunOutcome::Breakpoint(id) => {
+                let (_, breakpoint) = self
+                    .machine
+                    .breakpoints()
+                    .iter()
+                    .find(|(i, _)| *i == id)
+                    .unwrap();
+                println!("Breakpoint {}: {}", id, breakpoint);
+            }
+            RunOutcome::Blocked => println!("Waiting for input, use `input TEXT` or `eof`"),
+            RunOutcome::Halted => println!("Halted after {} cycles", self.machine.cycles()),
+            RunOutcome::Fault(fault) => println!("Fault: {}", fault),
+            RunOutcome::CycleLimit | RunOutcome::Deadline => {}
+        }
+        self.flush_output();
+        println!("{}", self.line(self.machine.finger()));
+    }
+
+    fn flush_output(&self) {
+        let output = self.io.drain_output();
+        if !output.is_empty() {
+            println!("{}", String::from_utf8_lossy(&output));
+        }
+    }
+
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(true),
+        };
+        let mut args: Vec<&str> = words.collect();
+        // Breakpoint conditions follow `if`, but `input` takes text verbatim
+        let condition = match args.iter().position(|&word| word == "if") {
+            Some(at) if command != "input" => Some(args.split_off(at)[1..].join(" ")),
+            _ => None,
+        };
+        let arg = |i: usize| {
+            args.get(i)
+                .cloned()
+                .ok_or_else(|| format!("`{}` needs more arguments, see `help`", command))
+        };
+        match command {
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(n) => number(n)?,
+                    None => 1,
+                };
+                self.run(Some(u64::from(count)));
+            }
+            "c" | "continue" => self.run(None),
+            "bk" | "back" => {
+                let count = match args.first() {
+                    Some(n) => number(n)?,
+                    None => 1,
+                };
+                for _ in 0..count {
+                    self.machine.step_back().map_err(|e| e.to_string())?;
+                }
+                println!("{}", self.line(self.machine.finger()));
+            }
+            "rc" | "reverse-continue" => {
+                let array = number(arg(0)?)?;
+                let offset = number(arg(1)?)?;
+                let cycle = self
+                    .machine
+                    .reverse_to_write(array, offset)
+                    .map_err(|e| e.to_string())?;
+                println!("Written on cycle {}", cycle);
+                println!("{}", self.line(self.machine.finger()));
+            }
+            "rewind" => {
+                let cycle = u64::from(number(arg(0)?)?);
+                self.machine.rewind(cycle).map_err(|e| e.to_string())?;
+                println!("{}", self.line(self.machine.finger()));
+            }
+            "b" | "break" | "bo" | "break-op" | "w" | "watch" | "rw" | "rwatch" | "break-alloc"
+            | "break-abandon" | "break-out" => {
+                let trigger = match command {
+                    "b" | "break" => Trigger::Finger(number(arg(0)?)? as usize),
+                    "bo" | "break-op" => Trigger::Opcode(opcode(arg(0)?)?),
+                    "w" | "watch" => Trigger::Write {
+                        array: number(arg(0)?)?,
+                        offsets: offsets(arg(1)?)?,
+                    },
+                    "rw" | "rwatch" => Trigger::Read {
+                        array: number(arg(0)?)?,
+                        offsets: offsets(arg(1)?)?,
+                    },
+                    "break-alloc" => Trigger::Allocate,
+                    "break-abandon" => {
+                        Trigger::Abandon(args.first().map(|a| number(a)).transpose()?)
+                    }
+                    _ => {
+                        let byte = number(arg(0)?)?;
+                        Trigger::Output(
+                            u8::try_from(byte).map_err(|_| format!("Not a byte: {}", byte))?,
+                        )
+                    }
+                };
+                let mut breakpoint = Breakpoint::new(trigger).map_err(|e| e.to_string())?;
+                if let Some(condition) = condition.as_deref() {
+                    breakpoint = breakpoint.when(predicate(condition)?);
+                }
+                let description = breakpoint.to_string();
+                let id = self.machine.add_breakpoint(breakpoint);
+                println!("Breakpoint {}: {}", id, description);
+            }
+            "i" | "info" => {
+                for (id, breakpoint) in self.machine.breakpoints() {
+                    println!("{}: {}", id, breakpoint);
+                }
+            }
+            "d" | "delete" => {
+                let id = number(arg(0)?)? as usize;
+                if self.machine.remove_breakpoint(id).is_none() {
+                    return Err(format!("No breakpoint {}", id));
+                }
+            }
+            "r" | "regs" => {
+                println!(
+                    "finger {:#010x}  cycles {}",
+                    self.machine.finger(),
+                    self.machine.cycles()
+                );
+                for (r, value) in self.machine.registers().iter().enumerate() {
+                    println!("r{} {:#010x} {:>10}", r, value, value);
+                }
+            }
+            "set" if arg(0)? == "finger" => {
+                let finger = number(arg(1)?)? as usize;
+                self.machine.set_finger(finger);
+                println!("{}", self.line(finger));
+            }
+            "set" => {
+                let register = register(arg(0)?)?;
+                let value = number(arg(1)?)?;
+                self.machine
+                    .set_register(register, value)
+                    .map_err(|e| e.to_string())?;
+            }
+            "poke" => {
+                let id = number(arg(0)?)?;
+                let offset = number(arg(1)?)?;
+                let value = number(arg(2)?)?;
+                self.machine
+                    .set_word(id, offset, value)
+                    .map_err(|e| e.to_string())?;
+            }
+            "arrays" => {
+                for (id, len) in self.machine.live_arrays() {
+                    println!("{:>6}: {} words", id, len);
+                }
+            }
+            "x" | "dump" => {
+                let id = number(arg(0)?)?;
+                let start = number(arg(1)?)? as usize;
+                let count = match args.get(2) {
+                    Some(n) => number(n)? as usize,
+                    None => 16,
+                };
+                let array = self
+                    .machine
+                    .array(id)
+                    .ok_or_else(|| format!("Array {} is not active", id))?;
+                let end = array.len().min(start.saturating_add(count));
+                for offset in (start..end).step_by(4) {
+                    let words = &array[offset..end.min(offset + 4)];
+                    let hex: Vec<String> = words.iter().map(|w| format!("{:08x}", w)).collect();
+                    println!("{:#010x}: {}", offset, hex.join(" "));
+                }
+            }
+            "dis" => {
+                let count = match args.first() {
+                    Some(n) => number(n)? as usize,
+                    None => 10,
+                };
+                let start = self.machine.finger().saturating_sub(count / 2);
+                for offset in start..start + count {
+                    println!("{}", self.line(offset));
+                }
+            }
+            "input" => {
+                let text = line.trim_start()["input".len()..].trim_start();
+                self.io.push_input(text.as_bytes());
+                self.io.push_input(b"\n");
+            }
+            "eof" => self.io.close(),
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return Ok(false),
+            _ => return Err(format!("Unknown command `{}`, see `help`", command)),
+        }
+        Ok(true)
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let mut path = None;
+    let mut input = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input" => input = Some(args.next().unwrap_or_else(|| usage())),
+            "-h" | "--help" => usage(),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    let mut scroll = File::open(&path).expect("Could not open scroll");
+    let io = QueueIo::new();
+    if let Some(input) = input {
+        let mut input = File::open(input).expect("Could not open input");
+        let mut bytes = Vec::new();
+        std::io::Read::read_to_end(&mut input, &mut bytes).expect("Could not read input");
+        io.push_input(&bytes);
+    }
+    let mut machine = Machine::new(io.clone(), &mut scroll);
+    machine.set_history(Some(HistoryConfig::default()));
+    let mut debugger = Debugger { machine, io };
+    println!("{}", debugger.line(0));
+
+    let stdin = stdin();
+    let mut last = String::new();
+    loop {
+        print!("(umdb) ");
+        stdout().flush().expect("Could not write prompt");
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        if line.trim().is_empty() {
+            line = last.clone();
+        }
+        match debugger.command(&line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => println!("{}", e),
+        }
+        last = line;
+    }
+}
@@ -0,0 +1,49 @@
+use cbv::disasm::{disassemble, Options};
+use cbv::read_scroll;
+
+use std::env;
+use std::fs::File;
+use std::io::{stdin, stdout, BufWriter};
+use std::process;
+
+const USAGE: &str =
+    "Usage: umdis [SCROLL] [--start OFFSET] [--end OFFSET] [--hex] [--mark-invalid]";
+
+fn number(s: Option<String>) -> usize {
+    let s = s.unwrap_or_else(|| usage());
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.unwrap_or_else(|_| usage())
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let mut options = Options::default();
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--start" => options.start = number(args.next()),
+            "--end" => options.end = Some(number(args.next())),
+            "--hex" => options.hex = true,
+            "--mark-invalid" => options.mark_invalid = true,
+            "-h" | "--help" => usage(),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    // Read from stdin, like term, when no scroll is named
+    let program = match path {
+        Some(path) => read_scroll(&mut File::open(path).expect("Could not open scroll")),
+        None => read_scroll(&mut stdin()),
+    };
+    let stdout = stdout();
+    // A closed pipe, as with `umdis scroll | head`, is not worth reporting
+    let _ = disassemble(&program, &options, &mut BufWriter::new(stdout.lock()));
+}
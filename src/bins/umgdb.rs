@@ -0,0 +1,83 @@
+use cbv::gdb::Stub;
+use cbv::io::QueueIo;
+use cbv::Machine;
+
+use std::env;
+use std::fs::{self, File};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::process;
+
+const USAGE: &str = "Usage: umgdb SCROLL [--tcp ADDRESS | --unix PATH] [--input FILE]";
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:1234";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let mut path = None;
+    let mut tcp = None;
+    let mut unix = None;
+    let mut input = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tcp" => tcp = Some(args.next().unwrap_or_else(|| usage())),
+            "--unix" => unix = Some(args.next().unwrap_or_else(|| usage())),
+            "--input" => input = Some(args.next().unwrap_or_else(|| usage())),
+            "-h" | "--help" => usage(),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    let mut scroll = File::open(path).expect("Could not open scroll");
+    let io = QueueIo::new();
+    if let Some(input) = input {
+        io.push_input(&fs::read(input).expect("Could not read input"));
+    }
+    let mut stub = Stub::new(Machine::new(io.clone(), &mut scroll), io);
+
+    match (tcp, unix) {
+        (Some(_), Some(_)) => usage(),
+        #[cfg(unix)]
+        (None, Some(path)) => {
+            // Replace a socket left behind by an earlier run, but nothing else
+            if fs::metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+                let _ = fs::remove_file(&path);
+            }
+            let listener = UnixListener::bind(&path).expect("Could not listen");
+            eprintln!("Listening on {}", path);
+            for stream in listener.incoming() {
+                let mut stream = stream.expect("Could not accept connection");
+                if let Err(e) = stub.serve(&mut stream) {
+                    eprintln!("Connection lost: {}", e);
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        (None, Some(_)) => {
+            eprintln!("Unix sockets are not supported here");
+            process::exit(2);
+        }
+        (address, None) => {
+            let address = address.as_deref().unwrap_or(DEFAULT_ADDRESS);
+            let listener = TcpListener::bind(address).expect("Could not listen");
+            eprintln!("Listening on {}", address);
+            for stream in listener.incoming() {
+                let mut stream = stream.expect("Could not accept connection");
+                // Packets are small and each waits on the last
+                let _ = stream.set_nodelay(true);
+                if let Err(e) = stub.serve(&mut stream) {
+                    eprintln!("Connection lost: {}", e);
+                }
+            }
+        }
+    }
+}
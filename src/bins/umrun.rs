@@ -0,0 +1,257 @@
+use cbv::coverage::Coverage;
+use cbv::io::QueueIo;
+use cbv::{Machine, RunLimit, RunOutcome};
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{stdin, stdout, Read, Write};
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: umrun SCROLL [--input FILE] [--input-text TEXT] [--allow-eof]
+             [--cycles N] [--timeout SECONDS] [-o FILE] [--expect FILE]
+             [--coverage DIR] [--lockstep]
+
+Runs SCROLL without a terminal, feeding it the input files and texts in the
+order given (`--input -` reads stdin). Input runs out when the scroll asks
+for more than it was given, unless --allow-eof hands it EOF instead.
+
+--lockstep instead runs SCROLL compiled by the JIT and interpreted side by
+side, up to --cycles, handing both EOF once input runs out, and reports the
+first instruction at which they differ. It needs the jit feature on x86-64
+Linux.
+
+Exit codes:
+  0  halted
+  1  faulted
+  2  bad arguments, or a file could not be read or written
+  3  stopped by --cycles or --timeout
+  4  ran out of input
+  5  halted, but the output differs from --expect
+  6  the compiled and interpreted runs differ, with --lockstep";
+
+const HALTED: i32 = 0;
+const FAULTED: i32 = 1;
+const FAILED: i32 = 2;
+const TIMED_OUT: i32 = 3;
+const STARVED: i32 = 4;
+const UNEXPECTED: i32 = 5;
+#[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+const DIVERGED: i32 = 6;
+
+// Output is written out between slices, so it shows up while a long run
+// is still going
+const SLICE: u64 = 1 << 20;
+
+struct Options {
+    scroll: String,
+    input: Vec<u8>,
+    allow_eof: bool,
+    cycles: Option<u64>,
+    timeout: Option<Duration>,
+    output: Option<String>,
+    expect: Option<String>,
+    coverage: Option<String>,
+    lockstep: bool,
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(FAILED);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("umrun: {}", message);
+    process::exit(FAILED);
+}
+
+fn options() -> Options {
+    let mut scroll = None;
+    let mut options = Options {
+        scroll: String::new(),
+        input: Vec::new(),
+        allow_eof: false,
+        cycles: None,
+        timeout: None,
+        output: None,
+        expect: None,
+        coverage: None,
+        lockstep: false,
+    };
+    let mut args = env::args().skip(1);
+    let value = |args: &mut dyn Iterator<Item = String>| args.next().unwrap_or_else(|| usage());
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input" => {
+                let path = value(&mut args);
+                let read = if path == "-" {
+                    stdin().read_to_end(&mut options.input).map(|_| ())
+                } else {
+                    fs::read(&path).map(|bytes| options.input.extend(bytes))
+                };
+                read.unwrap_or_else(|e| fail(format!("Could not read {}: {}", path, e)));
+            }
+            "--input-text" => options.input.extend(value(&mut args).bytes()),
+            "--allow-eof" => options.allow_eof = true,
+            "--cycles" => {
+                let cycles = value(&mut args);
+                options.cycles = Some(cycles.parse().unwrap_or_else(|_| usage()));
+            }
+            "--timeout" => {
+                let seconds: f64 = value(&mut args).parse().unwrap_or_else(|_| usage());
+                options.timeout =
+                    Some(Duration::try_from_secs_f64(seconds).unwrap_or_else(|_| usage()));
+            }
+            "-o" | "--output" => options.output = Some(value(&mut args)),
+            "--expect" => options.expect = Some(value(&mut args)),
+            "--coverage" => options.coverage = Some(value(&mut args)),
+            "--lockstep" => options.lockstep = true,
+            "-h" | "--help" => usage(),
+            _ if scroll.is_none() => scroll = Some(arg),
+            _ => usage(),
+        }
+    }
+    options.scroll = scroll.unwrap_or_else(|| usage());
+    options
+}
+
+#[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+fn lockstep(options: &Options) -> ! {
+    let scroll = fs::read(&options.scroll)
+        .unwrap_or_else(|e| fail(format!("Could not read {}: {}", options.scroll, e)));
+    let cycles = options.cycles.unwrap_or(u64::MAX);
+    match cbv::jit::lockstep(&scroll, &options.input, cycles) {
+        Ok(cycles) => {
+            eprintln!(
+                "umrun: compiled and interpreted runs agree for {} cycles",
+                cycles
+            );
+            process::exit(HALTED);
+        }
+        Err(divergence) => {
+            eprintln!("umrun: {}", divergence);
+            process::exit(DIVERGED);
+        }
+    }
+}
+
+#[cfg(not(all(feature = "jit", target_os = "linux", target_arch = "x86_64")))]
+fn lockstep(_: &Options) -> ! {
+    fail("--lockstep needs the jit feature on x86-64 Linux".to_string())
+}
+
+/// Run until the machine stops or a limit is reached, passing output on
+/// to `out` as it comes and to `kept` as well if given.
+fn run(
+    machine: &mut Machine,
+    io: &QueueIo,
+    options: &Options,
+    out: &mut dyn Write,
+    mut kept: Option<&mut Vec<u8>>,
+) -> RunOutcome {
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let start = machine.cycles();
+    loop {
+        let elapsed = machine.cycles() - start;
+        let slice = match options.cycles {
+            Some(cycles) => SLICE.min(cycles - elapsed),
+            None => SLICE,
+        };
+        let limit = RunLimit {
+            deadline,
+            ..RunLimit::cycles(slice)
+        };
+        let outcome = machine.run(limit);
+        let output = io.drain_output();
+        out.write_all(&output)
+            .and_then(|_| out.flush())
+            .unwrap_or_else(|e| fail(format!("Could not write output: {}", e)));
+        if let Some(kept) = &mut kept {
+            kept.extend(output);
+        }
+        match outcome {
+            RunOutcome::CycleLimit if Some(machine.cycles() - start) != options.cycles => {}
+            outcome => return outcome,
+        }
+    }
+}
+
+fn main() {
+    let options = options();
+    if options.lockstep {
+        lockstep(&options);
+    }
+    let mut scroll = File::open(&options.scroll)
+        .unwrap_or_else(|e| fail(format!("Could not open {}: {}", options.scroll, e)));
+    let io = QueueIo::new();
+    io.push_input(&options.input);
+    if options.allow_eof {
+        io.close();
+    }
+    let mut machine = Machine::new(io.clone(), &mut scroll);
+    let coverage = options
+        .coverage
+        .as_ref()
+        .map(|_| Arc::new(Mutex::new(Coverage::default())));
+    if let Some(coverage) = &coverage {
+        machine.set_tracer(Some(Box::new(Arc::clone(coverage))));
+    }
+
+    // Keep what was written only to check it against --expect
+    let mut written = Vec::new();
+    let kept = options.expect.as_ref().map(|_| &mut written);
+    let outcome = match &options.output {
+        Some(path) => {
+            let mut file = File::create(path)
+                .unwrap_or_else(|e| fail(format!("Could not create {}: {}", path, e)));
+            run(&mut machine, &io, &options, &mut file, kept)
+        }
+        None => run(&mut machine, &io, &options, &mut stdout().lock(), kept),
+    };
+
+    if let (Some(dir), Some(coverage)) = (&options.coverage, &coverage) {
+        coverage
+            .lock()
+            .unwrap()
+            .save(Path::new(dir))
+            .unwrap_or_else(|e| fail(format!("Could not write coverage to {}: {}", dir, e)));
+    }
+    let code = match outcome {
+        RunOutcome::Halted => match &options.expect {
+            Some(path) => {
+                let expected = fs::read(path)
+                    .unwrap_or_else(|e| fail(format!("Could not read {}: {}", path, e)));
+                match written.iter().zip(&expected).position(|(a, b)| a != b) {
+                    None if written.len() == expected.len() => HALTED,
+                    offset => {
+                        let offset = offset.unwrap_or(written.len().min(expected.len()));
+                        eprintln!("umrun: output differs from {} at byte {}", path, offset);
+                        UNEXPECTED
+                    }
+                }
+            }
+            None => HALTED,
+        },
+        RunOutcome::Fault(fault) => {
+            eprintln!("umrun: machine fault: {}", fault);
+            FAULTED
+        }
+        RunOutcome::CycleLimit | RunOutcome::Deadline => {
+            eprintln!("umrun: stopped after {} cycles", machine.cycles());
+            TIMED_OUT
+        }
+        RunOutcome::Blocked => {
+            eprintln!(
+                "umrun: out of input after {} cycles and {} bytes",
+                machine.cycles(),
+                options.input.len()
+            );
+            STARVED
+        }
+        RunOutcome::Breakpoint(_) => unreachable!("umrun sets no breakpoints"),
+    };
+    process::exit(code);
+}
@@ -1,10 +1,12 @@
-#![recursion_limit = "128"]
+#![recursion_limit = "256"]
 
 #[macro_use]
 extern crate stdweb;
 
 use stdweb::traits::IKeyboardEvent;
+use stdweb::{UnsafeTypedArray, Value};
 
+use cbv::snapshot;
 use cbv::webmachine::{Request, Response, WebMachine};
 
 use std::time::Duration;
@@ -14,7 +16,8 @@ use serde_derive::{Deserialize, Serialize};
 use yew::format::Json;
 use yew::services::reader::{File, FileData, ReaderService, ReaderTask};
 use yew::services::{
-    interval::IntervalTask, storage::Area, ConsoleService, IntervalService, StorageService, DialogService
+    interval::IntervalTask, storage::Area, ConsoleService, DialogService, IntervalService,
+    StorageService,
 };
 use yew::{html, ChangeData, Component, ComponentLink, Html, Renderable, ShouldRender};
 
@@ -46,6 +49,12 @@ struct BootMedia {
     selected: bool,
 }
 
+// Where a requested snapshot goes once the machine sends it
+enum SnapshotTarget {
+    Storage,
+    Download,
+}
+
 #[derive(Deserialize, Serialize)]
 struct ScriptMedia {
     data: Vec<u8>,
@@ -53,7 +62,6 @@ struct ScriptMedia {
     selected: bool,
 }
 
-
 struct Model {
     link: ComponentLink<Model>,
     machine: Box<Bridge<WebMachine>>,
@@ -71,6 +79,7 @@ struct Model {
     reader: ReaderService,
     read_tasks: Vec<ReaderTask>,
     local_storage: StorageService,
+    snapshot_target: Option<SnapshotTarget>,
 }
 
 impl Model {
@@ -81,10 +90,53 @@ impl Model {
 
     fn save_scripts(&mut self) {
         if let Ok(s) = serde_json::to_string(&self.script_media) {
-            self.local_storage
-                .store("scripts", Json(&s));
+            self.local_storage.store("scripts", Json(&s));
+        }
+    }
+
+    // localStorage only holds strings and its quota is a few megabytes, so
+    // the snapshot goes in as base64 rather than as JSON numbers. Written
+    // directly, as the storage service panics when the quota is hit
+    fn save_snapshot(&mut self, snapshot: &[u8]) -> Result<(), String> {
+        let text = snapshot::to_text(snapshot);
+        let stored = js! {
+            try {
+                localStorage.setItem("snapshot", @{text});
+                return null;
+            } catch (e) {
+                return e.message;
+            }
+        };
+        match stored {
+            Value::String(message) => Err(format!(
+                "Could not save the snapshot ({} bytes): {}",
+                snapshot.len(),
+                message
+            )),
+            _ => Ok(()),
         }
     }
+
+    fn stored_snapshot(&mut self) -> Option<Vec<u8>> {
+        self.local_storage
+            .restore::<Result<String, failure::Error>>("snapshot")
+            .ok()
+            .and_then(|s| snapshot::from_text(&s))
+    }
+}
+
+fn download(name: &str, data: &[u8]) {
+    let data = unsafe { UnsafeTypedArray::new(data) };
+    js! {
+        let blob = new Blob([new Uint8Array(@{data})], {type: "application/octet-stream"});
+        let link = document.createElement("a");
+        link.href = URL.createObjectURL(blob);
+        link.download = @{name};
+        link.click();
+        // Revoking straight away can cancel the download before it starts
+        let url = link.href;
+        setTimeout(function() { URL.revokeObjectURL(url); }, 1000);
+    }
 }
 
 enum Msg {
@@ -99,6 +151,10 @@ enum Msg {
     Loaded(FileData),
     ScriptMedia(Vec<u8>),
     RemoveScript(String),
+    Snapshot(SnapshotTarget),
+    Resume,
+    SnapshotFile(Vec<File>),
+    SnapshotLoaded(FileData),
 }
 
 impl Component for Model {
@@ -118,14 +174,15 @@ impl Component for Model {
         let scripts: Vec<ScriptMedia> = local_storage
             .restore::<Result<String, failure::Error>>("scripts")
             .ok()
-            .and_then(|s| -> Option<serde_json::Value> {serde_json::from_str(&s).ok()})
+            .and_then(|s| -> Option<serde_json::Value> { serde_json::from_str(&s).ok() })
             .and_then(|s| {
                 if let serde_json::Value::String(s) = s {
                     serde_json::from_str(&s).ok()
                 } else {
                     Some(Vec::new())
                 }
-            }).unwrap_or_default();
+            })
+            .unwrap_or_default();
 
         Model {
             link,
@@ -143,6 +200,7 @@ impl Component for Model {
             reader: ReaderService::new(),
             script_media: scripts,
             local_storage,
+            snapshot_target: None,
             boot_media: vec![
                 BootMedia {
                     url: "/media/umix_os.um".into(),
@@ -158,7 +216,6 @@ impl Component for Model {
         }
     }
 
-
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
         match msg {
             Msg::RemoveScript(s) => {
@@ -227,6 +284,55 @@ impl Component for Model {
                 }
                 true
             }
+            Msg::Machine(Response::Snapshot(snapshot)) => {
+                match (self.snapshot_target.take(), snapshot) {
+                    (Some(SnapshotTarget::Storage), Some(snapshot)) => {
+                        if let Err(e) = self.save_snapshot(&snapshot) {
+                            self.link.send_self(Msg::Machine(Response::Error(e)));
+                        }
+                    }
+                    (Some(SnapshotTarget::Download), Some(snapshot)) => {
+                        download("state.umsnap", &snapshot)
+                    }
+                    (_, None) => DialogService::new().alert("Nothing is running"),
+                    (None, _) => {}
+                }
+                false
+            }
+            Msg::Machine(Response::Error(e)) => {
+                DialogService::new().alert(&e);
+                false
+            }
+            Msg::Snapshot(target) => {
+                self.snapshot_target = Some(target);
+                self.machine.send(Request::Snapshot);
+                false
+            }
+            Msg::Resume => {
+                match self.stored_snapshot() {
+                    Some(snapshot) => {
+                        self.terminal = String::new();
+                        self.machine.send(Request::Restore(snapshot));
+                    }
+                    None => DialogService::new().alert("No saved snapshot"),
+                }
+                false
+            }
+            Msg::SnapshotFile(files) => {
+                for file in files.into_iter() {
+                    let task = {
+                        let callback = self.link.send_back(Msg::SnapshotLoaded);
+                        self.reader.read_file(file, callback)
+                    };
+                    self.read_tasks.push(task);
+                }
+                false
+            }
+            Msg::SnapshotLoaded(file) => {
+                self.terminal = String::new();
+                self.machine.send(Request::Restore(file.content));
+                false
+            }
             Msg::Ignore => false,
             Msg::Shutdown => {
                 self.terminal = String::new();
@@ -250,8 +356,7 @@ impl Component for Model {
                     selected: false,
                 });
                 if let Ok(s) = serde_json::to_string(&self.script_media) {
-                    self.local_storage
-                        .store("scripts", Json(&s));
+                    self.local_storage.store("scripts", Json(&s));
                 }
                 true
             }
@@ -292,7 +397,6 @@ fn digit_counter(value: usize) -> Html<Model> {
         7 => 'Z',
         8 => 'Y',
         _ => '_',
-
     };
     let mut number = format!("{:0>3}", value / usize::pow(10, (size - (size % 3)) as u32));
     html! {
@@ -305,13 +409,16 @@ fn digit_counter(value: usize) -> Html<Model> {
     }
 }
 
-
 impl Renderable<Model> for Model {
     fn view(&self) -> Html<Self> {
         let media_view = move |media: &BootMedia| -> Html<Self> {
             let url = media.url.clone();
             html!(
-                <li><div class="floppy red", onclick=|_| Msg::FetchMedia(url.clone()), >{media.name.clone()}</div></li>
+                <li>
+                    <div class="floppy red", onclick=|_| Msg::FetchMedia(url.clone()), >
+                        {media.name.clone()}
+                    </div>
+                </li>
             )
         };
         let script_view = move |media: &ScriptMedia| -> Html<Self> {
@@ -319,12 +426,12 @@ impl Renderable<Model> for Model {
             let name = media.name.clone();
             html!(
                 <li>
-                    
+
                     <div class="floppy black", onclick=|_| Msg::ScriptMedia(data.clone()), >
                         {media.name.clone()}
-                    </div>   
+                    </div>
                     <div class="trash", onclick=|_| Msg::RemoveScript(name.clone()), >
-                    </div> 
+                    </div>
                 </li>
             )
         };
@@ -341,14 +448,18 @@ impl Renderable<Model> for Model {
             </button>
             <div class="container",>
                 <div class="term-container",>
-                    <pre class="term-box",>< pre class="terminal", id="terminal", > {&self.terminal} </pre></pre>
+                    <pre class="term-box",>
+                        < pre class="terminal", id="terminal", > {&self.terminal} </pre>
+                    </pre>
                 </div>
                 <div class="machine-container",>
                     <input class="term-input",
                             type="text",
                             value=&self.text,
                             oninput=|input| Msg::UpdateText(input.value),
-                            onkeyup=|ev| if ev.key() == "Enter" {Msg::SendText} else {Msg::Ignore},></input>
+                            onkeyup=|ev| {
+                                if ev.key() == "Enter" { Msg::SendText } else { Msg::Ignore }
+                            },></input>
 
                     <div class="indicator",>
                         <h4>{"CYCLES PER 100mS"}</h4>
@@ -380,6 +491,22 @@ impl Renderable<Model> for Model {
                             }
                             Msg::Script(result)
                     },/>
+
+                    <h4>{"SNAPSHOTS"}</h4>
+                    <button onclick=|_| Msg::Snapshot(SnapshotTarget::Storage),>
+                        {"SAVE"}
+                    </button>
+                    <button onclick=|_| Msg::Resume,>{"RESUME"}</button>
+                    <button onclick=|_| Msg::Snapshot(SnapshotTarget::Download),>
+                        {"DOWNLOAD"}
+                    </button>
+                    <input type="file", multiple=false, onchange=|value| {
+                            let mut result = Vec::new();
+                            if let ChangeData::Files(files) = value {
+                                result.extend(files);
+                            }
+                            Msg::SnapshotFile(result)
+                    },/>
                 </div>
             </div>
             </>
@@ -389,4 +516,4 @@ impl Renderable<Model> for Model {
 
 fn main() {
     yew::start_app::<Model>();
-}
\ No newline at end of file
+}
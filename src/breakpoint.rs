@@ -0,0 +1,420 @@
+//! Stopping `Machine::run` before something of interest happens.
+//!
+//! A `Breakpoint` fires on the instruction at the finger, before it
+//! executes, when its `Trigger` matches and its `Predicate`, if any, holds
+//! for the registers. `run` returns `RunOutcome::Breakpoint` with the
+//! identifier `Machine::add_breakpoint` gave it, leaving the machine on the
+//! instruction. Running again carries on past the instruction it stopped
+//! on; any other, the first of a run included, can stop the machine.
+//!
+//! While any breakpoint is set the machine steps one instruction at a time,
+//! bypassing the threaded engine and the JIT.
+//!
+//! Breakpoints and predicates are checked when made, so one naming a
+//! register past r7 or an opcode past 13 is refused with a
+//! `BreakpointError`.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::disasm::character;
+use crate::Instruction::{self, *};
+use crate::{Machine, Pointers, MNEMONICS};
+
+/// What a breakpoint waits for.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Trigger {
+    /// The finger reaching an offset.
+    Finger(usize),
+    /// Any instruction with the opcode.
+    Opcode(u32),
+    /// `Index` of the array at an offset in the range.
+    Read {
+        array: u32,
+        offsets: RangeInclusive<u32>,
+    },
+    /// `Amend` of the array at an offset in the range.
+    Write {
+        array: u32,
+        offsets: RangeInclusive<u32>,
+    },
+    /// Any `Allocate`.
+    Allocate,
+    /// `Abandon` of the array, or of any array.
+    Abandon(Option<u32>),
+    /// `Out` of the byte.
+    Output(u8),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        }
+    }
+}
+
+/// Why a breakpoint or predicate could not be made.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BreakpointError {
+    NoRegister(usize),
+    NoOpcode(u32),
+}
+
+impl fmt::Display for BreakpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakpointError::NoRegister(register) => {
+                write!(f, "there is no register r{}", register)
+            }
+            BreakpointError::NoOpcode(opcode) => write!(f, "there is no opcode {}", opcode),
+        }
+    }
+}
+
+impl std::error::Error for BreakpointError {}
+
+/// A register compared with a value, e.g. `r3 >= 10`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Predicate {
+    register: usize,
+    comparison: Comparison,
+    value: u32,
+}
+
+impl Predicate {
+    pub fn new(
+        register: usize,
+        comparison: Comparison,
+        value: u32,
+    ) -> Result<Self, BreakpointError> {
+        if register >= 8 {
+            return Err(BreakpointError::NoRegister(register));
+        }
+        Ok(Predicate {
+            register,
+            comparison,
+            value,
+        })
+    }
+
+    pub fn register(&self) -> usize {
+        self.register
+    }
+
+    pub fn comparison(&self) -> Comparison {
+        self.comparison
+    }
+
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    pub fn holds(&self, registers: &[u32; 8]) -> bool {
+        let register = registers[self.register];
+        match self.comparison {
+            Comparison::Eq => register == self.value,
+            Comparison::Ne => register != self.value,
+            Comparison::Lt => register < self.value,
+            Comparison::Le => register <= self.value,
+            Comparison::Gt => register > self.value,
+            Comparison::Ge => register >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "r{} {} {:#x}",
+            self.register,
+            self.comparison.symbol(),
+            self.value
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Breakpoint {
+    trigger: Trigger,
+    predicate: Option<Predicate>,
+}
+
+impl Breakpoint {
+    pub fn new(trigger: Trigger) -> Result<Self, BreakpointError> {
+        if let Trigger::Opcode(opcode) = trigger {
+            if opcode as usize >= MNEMONICS.len() {
+                return Err(BreakpointError::NoOpcode(opcode));
+            }
+        }
+        Ok(Breakpoint {
+            trigger,
+            predicate: None,
+        })
+    }
+
+    pub fn trigger(&self) -> &Trigger {
+        &self.trigger
+    }
+
+    pub fn predicate(&self) -> Option<Predicate> {
+        self.predicate
+    }
+
+    /// Only fire while `predicate` holds.
+    pub fn when(self, predicate: Predicate) -> Self {
+        Breakpoint {
+            predicate: Some(predicate),
+            ..self
+        }
+    }
+
+    /// Whether `instruction`, about to execute at `finger` with `registers`,
+    /// fires the breakpoint.
+    pub fn fires(
+        &self,
+        finger: usize,
+        instruction: Option<Instruction>,
+        registers: &[u32; 8],
+    ) -> bool {
+        let r = registers;
+        let triggered = match (&self.trigger, instruction) {
+            (Trigger::Finger(at), _) => *at == finger,
+            (Trigger::Opcode(opcode), Some(instruction)) => instruction.opcode() == *opcode,
+            (Trigger::Read { array, offsets }, Some(Index(Pointers { b, c, .. }))) => {
+                r[b] == *array && offsets.contains(&r[c])
+            }
+            (Trigger::Write { array, offsets }, Some(Amend(Pointers { a, b, .. }))) => {
+                r[a] == *array && offsets.contains(&r[b])
+            }
+            (Trigger::Allocate, Some(Allocate(_))) => true,
+            (Trigger::Abandon(array), Some(Abandon(Pointers { c, .. }))) => {
+                array.is_none_or(|array| r[c] == array)
+            }
+            (Trigger::Output(byte), Some(Out(Pointers { c, .. }))) => r[c] == u32::from(*byte),
+            _ => false,
+        };
+        triggered && self.predicate.is_none_or(|p| p.holds(registers))
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let offsets = |offsets: &RangeInclusive<u32>| match (offsets.start(), offsets.end()) {
+            (start, end) if start == end => format!("offset {:#x}", start),
+            (start, end) => format!("offsets {:#x}-{:#x}", start, end),
+        };
+        match &self.trigger {
+            Trigger::Finger(finger) => write!(f, "finger {:#x}", finger),
+            Trigger::Opcode(opcode) => write!(f, "opcode {}", MNEMONICS[*opcode as usize]),
+            Trigger::Read { array, offsets: o } => {
+                write!(f, "read of array {} at {}", array, offsets(o))
+            }
+            Trigger::Write { array, offsets: o } => {
+                write!(f, "write to array {} at {}", array, offsets(o))
+            }
+            Trigger::Allocate => write!(f, "allocation"),
+            Trigger::Abandon(None) => write!(f, "abandon"),
+            Trigger::Abandon(Some(array)) => write!(f, "abandon of array {}", array),
+            Trigger::Output(byte) => match character(u32::from(*byte)) {
+                Some(c) => write!(f, "output of {:#04x} {}", byte, c),
+                None => write!(f, "output of {:#04x}", byte),
+            },
+        }?;
+        match &self.predicate {
+            Some(predicate) => write!(f, " if {}", predicate),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Machine {
+    /// Set a breakpoint, returning the identifier `run` reports it by.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        self.breakpoints.push((id, breakpoint));
+        id
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> Option<Breakpoint> {
+        let index = self.breakpoints.iter().position(|(i, _)| *i == id)?;
+        Some(self.breakpoints.remove(index).1)
+    }
+
+    /// Breakpoints and their identifiers, in the order they were set.
+    pub fn breakpoints(&self) -> &[(usize, Breakpoint)] {
+        &self.breakpoints
+    }
+
+    /// The first breakpoint the instruction at the finger fires.
+    pub fn breakpoint_hit(&self) -> Option<usize> {
+        let instruction = self.instruction().ok();
+        self.breakpoints
+            .iter()
+            .find(|(_, breakpoint)| breakpoint.fires(self.fin, instruction, &self.reg))
+            .map(|(id, _)| *id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asm::boot;
+    use crate::io::QueueIo;
+    use crate::{RunLimit, RunOutcome};
+
+    #[test]
+    fn test_breakpoints() {
+        let io = QueueIo::new();
+        let mut machine = boot(
+            "
+                    ortho r1, 4
+                    alloc r2, r1
+                    ortho r3, 1
+                    ortho r0, loop
+            loop:   amend r2, r5, r5
+                    index r6, r2, r5
+                    ortho r7, 'a'
+                    add r7, r7, r5
+                    out r7
+                    add r5, r5, r3
+                    load r4, r0
+            ",
+            io.clone(),
+        );
+        let write = Breakpoint::new(Trigger::Write {
+            array: 1,
+            offsets: 2..=3,
+        });
+        let write = machine.add_breakpoint(write.unwrap());
+        let output = Breakpoint::new(Trigger::Output(b'b'));
+        let output = machine.add_breakpoint(output.unwrap());
+        let read = Breakpoint::new(Trigger::Read {
+            array: 1,
+            offsets: 0..=3,
+        });
+        let predicate = Predicate::new(5, Comparison::Ge, 3).unwrap();
+        let read = machine.add_breakpoint(read.unwrap().when(predicate));
+
+        assert_eq!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Breakpoint(output)
+        );
+        assert_eq!(machine.finger(), 8);
+        assert_eq!(io.drain_output(), b"a");
+        // Running again executes the `out` it stopped on
+        assert_eq!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Breakpoint(write)
+        );
+        assert_eq!(machine.registers()[5], 2);
+        assert_eq!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Breakpoint(write)
+        );
+        assert_eq!(machine.registers()[5], 3);
+        assert_eq!(
+            machine.remove_breakpoint(write).unwrap().to_string(),
+            "write to array 1 at offsets 0x2-0x3"
+        );
+        assert_eq!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Breakpoint(read)
+        );
+        assert_eq!(machine.finger(), 5);
+        assert_eq!(io.drain_output(), b"bc");
+        assert!(matches!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Fault(_)
+        ));
+        assert_eq!(io.drain_output(), b"d");
+
+        let set: Vec<String> = machine
+            .breakpoints()
+            .iter()
+            .map(|(id, breakpoint)| format!("{} {}", id, breakpoint))
+            .collect();
+        assert_eq!(
+            set,
+            [
+                "1 output of 0x62 'b'",
+                "2 read of array 1 at offsets 0x0-0x3 if r5 >= 0x3"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_first_instruction() {
+        let mut machine = boot(
+            "
+                    ortho r1, 1
+                    ortho r0, 0
+                    load r0, r0
+            ",
+            QueueIo::new(),
+        );
+        let start = machine.add_breakpoint(Breakpoint::new(Trigger::Finger(0)).unwrap());
+        assert_eq!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Breakpoint(start)
+        );
+        assert_eq!(machine.cycles(), 0);
+        // Single cycles step past the breakpoint and stop on it next time
+        // round the loop
+        let outcomes: Vec<RunOutcome> = (0..4).map(|_| machine.run(RunLimit::cycles(1))).collect();
+        assert_eq!(
+            outcomes,
+            [
+                RunOutcome::CycleLimit,
+                RunOutcome::CycleLimit,
+                RunOutcome::CycleLimit,
+                RunOutcome::Breakpoint(start)
+            ]
+        );
+        assert_eq!(machine.cycles(), 3);
+    }
+
+    #[test]
+    fn test_triggers() {
+        let registers = [0, 1, 2, 3, 4, 5, 6, 7];
+        let fires = |trigger: Trigger, word: u32| {
+            let breakpoint = Breakpoint::new(trigger).unwrap();
+            breakpoint.fires(0, Instruction::decode(word), &registers)
+        };
+        // alloc r1, r2; abandon r3; opcode 14 does not decode
+        assert!(fires(Trigger::Allocate, 0x8000_000A));
+        assert!(fires(Trigger::Opcode(8), 0x8000_000A));
+        assert!(fires(Trigger::Abandon(None), 0x9000_0003));
+        assert!(fires(Trigger::Abandon(Some(3)), 0x9000_0003));
+        assert!(!fires(Trigger::Abandon(Some(4)), 0x9000_0003));
+        assert!(fires(Trigger::Finger(0), 0xE000_0000));
+        assert!(!fires(Trigger::Opcode(13), 0xE000_0000));
+
+        assert_eq!(
+            Breakpoint::new(Trigger::Opcode(14)),
+            Err(BreakpointError::NoOpcode(14))
+        );
+        assert_eq!(
+            Predicate::new(8, Comparison::Eq, 0),
+            Err(BreakpointError::NoRegister(8))
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! The framing shared by the binary formats: a magic string, a big-endian
+//! `u16` version, the payload and a CRC-32 of everything before it.
+
+/// Why a framed file could not be read.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Malformed {
+    NotRecognised,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Corrupt(&'static str),
+}
+
+/// Start a frame; finish it with `seal`.
+pub(crate) fn frame(magic: &[u8], version: u16) -> Vec<u8> {
+    let mut out = magic.to_vec();
+    out.extend_from_slice(&version.to_be_bytes());
+    out
+}
+
+pub(crate) fn seal(out: &mut Vec<u8>) {
+    let checksum = crc32(out);
+    put_u32(out, checksum);
+}
+
+/// Check a frame and return a reader over its payload.
+pub(crate) fn open<'a>(
+    bytes: &'a [u8],
+    magic: &[u8],
+    version: u16,
+) -> Result<Payload<'a>, Malformed> {
+    let header = magic.len() + 2;
+    if bytes.len() < header || &bytes[..magic.len()] != magic {
+        return Err(Malformed::NotRecognised);
+    }
+    let found = u16::from_be_bytes([bytes[magic.len()], bytes[magic.len() + 1]]);
+    if found != version {
+        return Err(Malformed::UnsupportedVersion(found));
+    }
+    if bytes.len() < header + 4 {
+        return Err(Malformed::Corrupt("truncated"));
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    if crc32(body) != u32::from_be_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]) {
+        return Err(Malformed::ChecksumMismatch);
+    }
+    Ok(Payload {
+        bytes: &body[header..],
+    })
+}
+
+pub(crate) fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+pub(crate) fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+/// A `u32` length followed by the bytes.
+pub(crate) fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+pub(crate) struct Payload<'a> {
+    pub(crate) bytes: &'a [u8],
+}
+
+impl<'a> Payload<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Malformed> {
+        if self.bytes.len() < len {
+            return Err(Malformed::Corrupt("truncated"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, Malformed> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn flag(&mut self) -> Result<bool, Malformed> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Malformed::Corrupt("invalid flag")),
+        }
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, Malformed> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, Malformed> {
+        Ok(u64::from(self.u32()?) << 32 | u64::from(self.u32()?))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], Malformed> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn finish(self) -> Result<(), Malformed> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(Malformed::Corrupt("trailing bytes"))
+        }
+    }
+}
+
+/// CRC-32 as used by zlib and PNG.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_frame() {
+        let mut out = frame(b"TEST", 3);
+        put_u64(&mut out, 7);
+        seal(&mut out);
+        let mut payload = open(&out, b"TEST", 3).unwrap();
+        assert_eq!(payload.u64(), Ok(7));
+        assert_eq!(payload.finish(), Ok(()));
+        assert_eq!(
+            open(&out, b"TEST", 4).err(),
+            Some(Malformed::UnsupportedVersion(3))
+        );
+        out[8] ^= 1;
+        assert_eq!(
+            open(&out, b"TEST", 3).err(),
+            Some(Malformed::ChecksumMismatch)
+        );
+    }
+}
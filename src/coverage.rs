@@ -0,0 +1,260 @@
+//! Finding which instructions of a program have run.
+//!
+//! A `Coverage` is a `Tracer` that counts executions of each offset of
+//! each program the machine runs: what array 0 held when tracing started,
+//! and every array `Load`ed into array 0 since. A program loaded again with
+//! the same words adds to the counts of its earlier load. Offsets are
+//! counted against the words a program had when loaded, even if it amends
+//! itself afterwards.
+//!
+//! Words that do not decode are taken to be data and left out of the totals
+//! unless they ran, having been amended into instructions.
+//!
+//! `report` lists each program with its counts beside the disassembly, as
+//! gcov does: `#####` for instructions that never ran and `-` for data.
+//! `lcov` writes a tracefile whose line numbers are offsets plus one, the
+//! lines of `umdis`'s listing of the program, so `genhtml` can render it
+//! next to listings `save` writes.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::disasm::{self, Options};
+use crate::trace::{TraceEvent, Tracer};
+use crate::Instruction;
+
+/// Executions of one program's offsets.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProgramCoverage {
+    /// The array the program was loaded from, 0 for the first.
+    pub array: u32,
+    pub words: Arc<Vec<u32>>,
+    /// Executions of each offset.
+    pub counts: Vec<u64>,
+}
+
+impl ProgramCoverage {
+    fn is_instruction(&self, offset: usize) -> bool {
+        self.counts[offset] > 0 || Instruction::decode(self.words[offset]).is_some()
+    }
+
+    /// Offsets holding instructions, the ones coverage is measured against.
+    pub fn instructions(&self) -> usize {
+        (0..self.words.len())
+            .filter(|&offset| self.is_instruction(offset))
+            .count()
+    }
+
+    /// Offsets that ran at least once.
+    pub fn executed(&self) -> usize {
+        self.counts.iter().filter(|&&count| count > 0).count()
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Coverage {
+    programs: Vec<ProgramCoverage>,
+    // Indices of `programs` by a hash of their words, so a program loaded
+    // again is only compared with those it may equal
+    hashes: HashMap<u64, Vec<usize>>,
+    current: usize,
+}
+
+impl Tracer for Coverage {
+    fn trace(&mut self, event: &TraceEvent) {
+        if let Some(program) = self.programs.get_mut(self.current) {
+            if let Some(count) = program.counts.get_mut(event.finger) {
+                *count += 1;
+            }
+        }
+    }
+
+    fn program(&mut self, array: u32, words: &Arc<Vec<u32>>) {
+        // Loading an array nothing has amended since shares its words
+        let shared = self
+            .programs
+            .iter()
+            .position(|program| Arc::ptr_eq(&program.words, words));
+        if let Some(index) = shared {
+            self.current = index;
+            return;
+        }
+        let mut hasher = DefaultHasher::new();
+        words.hash(&mut hasher);
+        let programs = &mut self.programs;
+        let candidates = self.hashes.entry(hasher.finish()).or_default();
+        let known = candidates
+            .iter()
+            .cloned()
+            .find(|&index| programs[index].words == *words);
+        self.current = known.unwrap_or_else(|| {
+            programs.push(ProgramCoverage {
+                array,
+                words: Arc::clone(words),
+                counts: vec![0; words.len()],
+            });
+            candidates.push(programs.len() - 1);
+            programs.len() - 1
+        });
+    }
+}
+
+fn percent(count: usize, total: usize) -> f64 {
+    count as f64 * 100.0 / total.max(1) as f64
+}
+
+impl Coverage {
+    /// Programs in the order they were first loaded.
+    pub fn programs(&self) -> &[ProgramCoverage] {
+        &self.programs
+    }
+
+    /// Write each program's disassembly with the times each offset ran.
+    pub fn report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let options = Options::default();
+        for (index, program) in self.programs.iter().enumerate() {
+            let (executed, instructions) = (program.executed(), program.instructions());
+            writeln!(
+                out,
+                "; program {}, loaded from array {}: {} of {} instructions executed ({:.1}%)",
+                index,
+                program.array,
+                executed,
+                instructions,
+                percent(executed, instructions)
+            )?;
+            for (offset, &word) in program.words.iter().enumerate() {
+                let count = match program.counts[offset] {
+                    0 if program.is_instruction(offset) => "#####".to_string(),
+                    0 => "-".to_string(),
+                    count => count.to_string(),
+                };
+                writeln!(
+                    out,
+                    "{:>10}:{}",
+                    count,
+                    disasm::line(offset, word, &options)
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write an lcov tracefile, naming each program's listing by `source`
+    /// called with its index.
+    pub fn lcov(&self, source: &dyn Fn(usize) -> String, out: &mut dyn Write) -> io::Result<()> {
+        for (index, program) in self.programs.iter().enumerate() {
+            writeln!(out, "TN:")?;
+            writeln!(out, "SF:{}", source(index))?;
+            for (offset, count) in program.counts.iter().enumerate() {
+                if program.is_instruction(offset) {
+                    writeln!(out, "DA:{},{}", offset + 1, count)?;
+                }
+            }
+            writeln!(out, "LF:{}", program.instructions())?;
+            writeln!(out, "LH:{}", program.executed())?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+
+    /// Write into `dir` a listing of each program, `programN.uma`, the
+    /// report as `coverage.txt` and the tracefile as `coverage.info`.
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let listing = |index: usize| dir.join(format!("program{}.uma", index));
+        for (index, program) in self.programs.iter().enumerate() {
+            let mut out = BufWriter::new(File::create(listing(index))?);
+            disasm::disassemble(&program.words, &Options::default(), &mut out)?;
+            out.flush()?;
+        }
+        let mut out = BufWriter::new(File::create(dir.join("coverage.txt"))?);
+        self.report(&mut out)?;
+        out.flush()?;
+        let mut out = BufWriter::new(File::create(dir.join("coverage.info"))?);
+        self.lcov(&|index| listing(index).display().to_string(), &mut out)?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::asm::boot;
+    use crate::io::QueueIo;
+    use crate::{RunLimit, RunOutcome};
+
+    #[test]
+    fn test_coverage() {
+        // Builds a one word program, `halt`, in array 1 and loads it
+        let source = "
+                    ortho r1, 1
+                    alloc r2, r1
+                    ortho r4, 7
+                    ortho r5, 0x1000000
+                    mul r4, r4, r5
+                    ortho r6, 16
+                    mul r4, r4, r6
+                    amend r2, r0, r4
+                    load r2, r0
+                    .word 0xe0000000
+                    halt
+        ";
+        let mut machine = boot(source, QueueIo::new());
+        let coverage = Arc::new(Mutex::new(Coverage::default()));
+        machine.set_tracer(Some(Box::new(Arc::clone(&coverage))));
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Halted);
+
+        let coverage = coverage.lock().unwrap();
+        let programs = coverage.programs();
+        assert_eq!(programs.len(), 2);
+        assert_eq!(
+            (programs[0].executed(), programs[0].instructions()),
+            (9, 10)
+        );
+        assert_eq!(programs[1].array, 1);
+        assert_eq!(programs[1].counts, [1]);
+
+        let mut report = Vec::new();
+        coverage.report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            "; program 0, loaded from array 0: 9 of 10 instructions executed (90.0%)"
+        );
+        assert_eq!(
+            lines[9],
+            "         1:    load r2, r0              ; 00000008"
+        );
+        assert_eq!(
+            lines[10],
+            "         -:    .word 0xe0000000         ; 00000009"
+        );
+        assert_eq!(
+            lines[11],
+            "     #####:    halt                     ; 0000000a"
+        );
+        assert_eq!(
+            lines[12],
+            "; program 1, loaded from array 1: 1 of 1 instructions executed (100.0%)"
+        );
+
+        let mut lcov = Vec::new();
+        coverage
+            .lcov(&|index| format!("program{}.uma", index), &mut lcov)
+            .unwrap();
+        let lcov = String::from_utf8(lcov).unwrap();
+        assert!(lcov.starts_with("TN:\nSF:program0.uma\nDA:1,1\n"));
+        assert!(lcov.contains("DA:9,1\nDA:11,0\nLF:10\nLH:9\nend_of_record\n"));
+        assert!(lcov.ends_with("SF:program1.uma\nDA:1,1\nLF:1\nLH:1\nend_of_record\n"));
+    }
+}
@@ -0,0 +1,136 @@
+//! Listing a scroll as assembly.
+//!
+//! Every platter becomes one line the assembler accepts, so a listing
+//! assembles back to the same words. Instructions are written in the
+//! syntax of `Instruction`'s `Display`. Words whose opcode is undefined, or
+//! which set bits no field of their instruction covers, are written as
+//! `.word` data instead. A comment after each line gives the offset and,
+//! for `ortho`, the immediate as a character where it is printable.
+
+use std::io::{self, Write};
+
+use crate::Instruction::{self, *};
+use crate::{OrthoPointers, Pointers};
+
+/// What `disassemble` lists and how.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Options {
+    /// First offset listed.
+    pub start: usize,
+    /// Offset to stop before, or the end of the scroll.
+    pub end: Option<usize>,
+    /// Show each platter in hex next to its offset.
+    pub hex: bool,
+    /// Note which words cannot be executed.
+    pub mark_invalid: bool,
+}
+
+/// The instruction the assembler produces for `instruction`'s text, which
+/// leaves out the registers it does not use.
+fn canonical(instruction: Instruction) -> Instruction {
+    let unused = |a, b, c| Pointers { a, b, c };
+    match instruction {
+        Halt(_) => Halt(unused(0, 0, 0)),
+        Allocate(Pointers { b, c, .. }) => Allocate(unused(0, b, c)),
+        Load(Pointers { b, c, .. }) => Load(unused(0, b, c)),
+        Abandon(Pointers { c, .. }) => Abandon(unused(0, 0, c)),
+        Out(Pointers { c, .. }) => Out(unused(0, 0, c)),
+        In(Pointers { c, .. }) => In(unused(0, 0, c)),
+        other => other,
+    }
+}
+
+/// `value` as a quoted character, if it is one worth showing.
+pub fn character(value: u32) -> Option<String> {
+    match value {
+        0x20..=0x7E | 0x09 | 0x0A | 0x0D => {
+            Some(format!("'{}'", char::from(value as u8).escape_default()))
+        }
+        _ => None,
+    }
+}
+
+/// The assembly for a single platter: the instruction when it assembles
+/// back to `word`, otherwise a `.word` directive.
+pub fn source(word: u32) -> String {
+    match Instruction::decode(word) {
+        Some(instruction) if canonical(instruction).encode() == word => instruction.to_string(),
+        _ => format!(".word {:#010x}", word),
+    }
+}
+
+/// What the comment says about `word` beyond its offset.
+fn note(word: u32, options: &Options) -> Option<String> {
+    match Instruction::decode(word) {
+        Some(instruction) if canonical(instruction).encode() != word => {
+            Some(format!("{} (unused bits set)", instruction))
+        }
+        Some(Ortho(OrthoPointers { value, .. })) => character(value),
+        Some(_) => None,
+        None if options.mark_invalid => Some(format!("invalid opcode {}", word >> 28)),
+        None => None,
+    }
+}
+
+/// One line of the listing for `word` at `offset`.
+pub fn line(offset: usize, word: u32, options: &Options) -> String {
+    let mut comment = format!("{:08x}", offset);
+    if options.hex {
+        comment.push_str(&format!("  {:08x}", word));
+    }
+    if let Some(note) = note(word, options) {
+        comment.push_str("  ");
+        comment.push_str(&note);
+    }
+    format!("    {:<24} ; {}", source(word), comment)
+}
+
+/// Write the listing of `program` selected by `options`.
+pub fn disassemble(program: &[u32], options: &Options, out: &mut dyn Write) -> io::Result<()> {
+    let end = options.end.unwrap_or(program.len());
+    for (offset, &word) in program.iter().enumerate().take(end).skip(options.start) {
+        writeln!(out, "{}", line(offset, word, options))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source() {
+        assert_eq!(source(0xD200_0041), "ortho r1, 65");
+        assert_eq!(source(0x8000_000A), "alloc r1, r2");
+        assert_eq!(source(0x7000_0000), "halt");
+        // An `alloc` naming a register in `a`, which it ignores
+        assert_eq!(source(0x8000_004A), ".word 0x8000004a");
+        assert_eq!(source(0xE000_0000), ".word 0xe0000000");
+        assert_eq!(character(65).as_deref(), Some("'A'"));
+        assert_eq!(character(10).as_deref(), Some("'\\n'"));
+        assert_eq!(character(200), None);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = [0xD200_0041, 0xA000_0001, 0x7000_0100, 0xF000_0000];
+        let options = Options {
+            start: 1,
+            hex: true,
+            mark_invalid: true,
+            ..Options::default()
+        };
+        let mut out = Vec::new();
+        disassemble(&program, &options, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "    out r1                   ; 00000001  a0000001\n\
+             \x20   .word 0x70000100         ; 00000002  70000100  halt (unused bits set)\n\
+             \x20   .word 0xf0000000         ; 00000003  f0000000  invalid opcode 15\n"
+        );
+        assert_eq!(
+            line(0, program[0], &Options::default()),
+            "    ortho r1, 65             ; 00000000  'A'"
+        );
+    }
+}
@@ -0,0 +1,786 @@
+//! Debugging a machine from GDB, or any front-end speaking the GDB remote
+//! serial protocol, over a TCP or Unix socket.
+//!
+//! The registers are `r0` to `r7` and then `pc`, 32 bits each and sent
+//! big-endian like the words of a scroll, so GDB wants `set endian big`.
+//!
+//! Memory is addressed in bytes, each word big-endian. The upper 32 bits of
+//! an address pick the array and the lower 32 the byte within it, that is
+//! `offset * 4 + byte`. Array 0, the program, starts at address 0 and `pc`
+//! is the finger times 4, so code addresses need no decoding; array 7
+//! starts at 0x7_0000_0000.
+//!
+//! Breakpoints, software or hardware, stop when the finger reaches their
+//! address. Watchpoints stop after an `amend` (`watch`), an `index`
+//! (`rwatch`) or either (`awatch`) of a word they cover. Output the machine
+//! writes is passed on as console output; input is queued with `monitor
+//! input TEXT`, and `monitor eof` closes it.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use crate::breakpoint::{Breakpoint, Trigger};
+use crate::io::QueueIo;
+use crate::Instruction::{Amend, Index};
+use crate::{AccessError, Machine, Pointers, RunLimit, RunOutcome};
+
+/// Cycles run between checks for an interrupt from the client.
+const SLICE: u64 = 100_000;
+
+const INTERRUPT: u8 = 0x03;
+
+/// The longest packet the stub takes or sends, advertised in `qSupported`.
+/// Memory is read in hex, two characters a byte.
+const PACKET_SIZE: u64 = 0x1000;
+
+const MONITOR_HELP: &str = "\
+input TEXT   queue TEXT and a newline as input
+eof          close the input
+cycles       print the cycles executed
+";
+
+/// A client connection, which must be able to check for an interrupt
+/// without waiting while the machine runs.
+pub trait Connection: Read + Write {
+    /// The next byte the client has sent, or `None` if there is none yet.
+    fn poll(&mut self) -> io::Result<Option<u8>>;
+}
+
+macro_rules! socket_connection {
+    ($stream:ty) => {
+        impl Connection for $stream {
+            fn poll(&mut self) -> io::Result<Option<u8>> {
+                self.set_nonblocking(true)?;
+                let mut byte = [0];
+                let read = self.read(&mut byte);
+                self.set_nonblocking(false)?;
+                match read {
+                    Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(_) => Ok(Some(byte[0])),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    };
+}
+
+socket_connection!(TcpStream);
+#[cfg(unix)]
+socket_connection!(UnixStream);
+
+/// What a stop reply describes, remembered for `?`.
+const STOPPED: &str = "S05";
+
+/// Breakpoint and watchpoint kinds, numbered as in `Z` packets.
+const SOFTWARE: u8 = 0;
+const HARDWARE: u8 = 1;
+const WRITE: u8 = 2;
+const READ: u8 = 3;
+const ACCESS: u8 = 4;
+
+pub struct Stub {
+    machine: Machine,
+    io: QueueIo,
+    /// Machine breakpoints set for each `Z` packet's kind, address and
+    /// length.
+    points: HashMap<(u8, u64, u64), Vec<usize>>,
+    /// Bytes taken while polling for an interrupt, read before the
+    /// connection.
+    received: VecDeque<u8>,
+    no_ack: bool,
+    stop: String,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn number(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+/// The `ADDR,LENGTH` of `m`, `M` and `Z` packets.
+fn range(s: &str) -> Option<(u64, u64)> {
+    let (address, length) = s.split_once(',')?;
+    Some((number(address)?, number(length)?))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn target_xml() -> String {
+    let mut registers = String::new();
+    for r in 0..8 {
+        registers += &format!(
+            "    <reg name=\"r{}\" bitsize=\"32\" type=\"uint32\"/>\n",
+            r
+        );
+    }
+    format!(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n  <feature name=\"org.wumixos.um\">\n\
+         {}    <reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\"/>\n  </feature>\n</target>\n",
+        registers
+    )
+}
+
+impl Stub {
+    /// Serve `machine`, which must have been booted with `io`.
+    pub fn new(machine: Machine, io: QueueIo) -> Self {
+        Stub {
+            machine,
+            io,
+            points: HashMap::new(),
+            received: VecDeque::new(),
+            no_ack: false,
+            stop: STOPPED.to_string(),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// Answer a client until it detaches, kills the machine or hangs up.
+    /// The machine and its breakpoints are kept for the next client.
+    pub fn serve(&mut self, conn: &mut dyn Connection) -> io::Result<()> {
+        // Every connection starts out acknowledging packets
+        self.no_ack = false;
+        while let Some(packet) = self.receive(conn)? {
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            match packet.as_str() {
+                "k" => break,
+                "D" | "D;1" => {
+                    self.send(conn, "OK")?;
+                    break;
+                }
+                _ => {}
+            }
+            let reply = self.handle(&packet, conn)?;
+            self.send(conn, &reply)?;
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self, conn: &mut dyn Connection) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.received.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0];
+        loop {
+            return match conn.read(&mut byte) {
+                Ok(0) => Ok(None),
+                Ok(_) => Ok(Some(byte[0])),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+        }
+    }
+
+    /// The next packet, or an interrupt as a packet of its own, or `None`
+    /// once the client hangs up.
+    fn receive(&mut self, conn: &mut dyn Connection) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_byte(conn)? {
+                None => return Ok(None),
+                Some(INTERRUPT) => return Ok(Some(vec![INTERRUPT])),
+                Some(b'$') => {}
+                // Acknowledgements, and anything else between packets
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte(conn)? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut sum = [0; 2];
+            for digit in &mut sum {
+                *digit = self.read_byte(conn)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            }
+            if self.no_ack {
+                return Ok(Some(data));
+            }
+            let sum = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if sum == Some(checksum(&data)) {
+                conn.write_all(b"+")?;
+                return Ok(Some(data));
+            }
+            conn.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, conn: &mut dyn Connection, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            conn.write_all(packet.as_bytes())?;
+            conn.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte(conn)? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Show `text` on the client's console.
+    fn console(&mut self, conn: &mut dyn Connection, text: &[u8]) -> io::Result<()> {
+        for chunk in text.chunks(256) {
+            self.send(conn, &format!("O{}", hex(chunk)))?;
+        }
+        Ok(())
+    }
+
+    fn forward_output(&mut self, conn: &mut dyn Connection) -> io::Result<()> {
+        let output = self.io.drain_output();
+        self.console(conn, &output)
+    }
+
+    fn handle(&mut self, packet: &str, conn: &mut dyn Connection) -> io::Result<String> {
+        let error = || "E01".to_string();
+        let reply = match packet {
+            "\u{3}" => "S02".to_string(),
+            "?" => self.stop.clone(),
+            "g" => {
+                let mut registers = self.machine.registers().to_vec();
+                registers.push(self.pc());
+                registers.iter().map(|r| format!("{:08x}", r)).collect()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "QStartNoAckMode" => "OK".to_string(),
+            "vCont?" => "vCont;c;C;s;S".to_string(),
+            _ if packet.starts_with("qSupported") => format!(
+                "PacketSize={:x};QStartNoAckMode+;\
+                 swbreak+;hwbreak+;qXfer:features:read+;vContSupported+",
+                PACKET_SIZE
+            ),
+            _ if packet.starts_with('H') || packet.starts_with('T') => "OK".to_string(),
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                let xml = target_xml();
+                match range(&packet["qXfer:features:read:target.xml:".len()..]) {
+                    Some((offset, length)) => {
+                        let start = (offset as usize).min(xml.len());
+                        let end = start.saturating_add(length as usize).min(xml.len());
+                        let more = if end < xml.len() { 'm' } else { 'l' };
+                        format!("{}{}", more, &xml[start..end])
+                    }
+                    None => error(),
+                }
+            }
+            _ if packet.starts_with("qRcmd,") => match unhex(&packet["qRcmd,".len()..]) {
+                Some(command) => {
+                    let text = self.monitor(&String::from_utf8_lossy(&command));
+                    self.console(conn, text.as_bytes())?;
+                    "OK".to_string()
+                }
+                None => error(),
+            },
+            _ if packet.starts_with('G') => match unhex(&packet[1..]) {
+                Some(bytes) if bytes.len() == 36 => {
+                    let set = bytes.chunks(4).enumerate().try_for_each(|(r, word)| {
+                        self.set_register(
+                            r,
+                            u32::from_be_bytes([word[0], word[1], word[2], word[3]]),
+                        )
+                    });
+                    match set {
+                        Ok(()) => "OK".to_string(),
+                        Err(_) => error(),
+                    }
+                }
+                _ => error(),
+            },
+            _ if packet.starts_with('p') => match number(&packet[1..]) {
+                Some(r) if r < 8 => format!("{:08x}", self.machine.registers()[r as usize]),
+                Some(8) => format!("{:08x}", self.pc()),
+                _ => error(),
+            },
+            _ if packet.starts_with('P') => {
+                let assignment = packet[1..].split_once('=');
+                match assignment.and_then(|(r, v)| Some((number(r)?, unhex(v)?))) {
+                    Some((r, v)) if v.len() == 4 => {
+                        let value = u32::from_be_bytes([v[0], v[1], v[2], v[3]]);
+                        match self.set_register(r as usize, value) {
+                            Ok(()) => "OK".to_string(),
+                            Err(_) => error(),
+                        }
+                    }
+                    _ => error(),
+                }
+            }
+            _ if packet.starts_with('m') => match range(&packet[1..]) {
+                Some((address, length)) => {
+                    let length = length.min(PACKET_SIZE / 2);
+                    let bytes: Vec<u8> = (address..address.saturating_add(length))
+                        .map_while(|address| self.byte(address))
+                        .collect();
+                    if bytes.is_empty() && length > 0 {
+                        error()
+                    } else {
+                        hex(&bytes)
+                    }
+                }
+                None => error(),
+            },
+            _ if packet.starts_with('M') => {
+                let write = packet[1..].split_once(':');
+                match write.and_then(|(r, data)| Some((range(r)?, unhex(data)?))) {
+                    Some(((address, _), data)) => {
+                        let written = data
+                            .iter()
+                            .zip(address..)
+                            .all(|(&byte, address)| self.set_byte(address, byte));
+                        if written {
+                            "OK".to_string()
+                        } else {
+                            error()
+                        }
+                    }
+                    None => error(),
+                }
+            }
+            _ if packet.starts_with('Z') || packet.starts_with('z') => {
+                let mut fields = packet[1..].splitn(2, ',');
+                let kind = fields.next().and_then(|k| k.parse::<u8>().ok());
+                match (kind, fields.next().and_then(range)) {
+                    (Some(kind), Some((address, length))) if kind <= ACCESS => {
+                        let done = if packet.starts_with('Z') {
+                            self.insert(kind, address, length)
+                        } else {
+                            self.points.remove(&(kind, address, length)).map(|ids| {
+                                for id in ids {
+                                    self.machine.remove_breakpoint(id);
+                                }
+                            })
+                        };
+                        if done.is_some() {
+                            "OK".to_string()
+                        } else {
+                            error()
+                        }
+                    }
+                    _ => String::new(),
+                }
+            }
+            _ if packet.starts_with('c') || packet.starts_with('s') => {
+                if let Some(address) = number(&packet[1..]) {
+                    self.machine.set_finger((address / 4) as usize);
+                }
+                self.resume(packet.starts_with('s'), conn)?
+            }
+            _ if packet.starts_with("vCont;") => {
+                // Only one thread, so the first action is the one for it
+                let action = packet["vCont;".len()..].chars().next();
+                match action {
+                    Some('c') | Some('C') => self.resume(false, conn)?,
+                    Some('s') | Some('S') => self.resume(true, conn)?,
+                    _ => error(),
+                }
+            }
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn monitor(&mut self, command: &str) -> String {
+        let command = command.trim_start();
+        let (name, rest) = command.split_once(' ').unwrap_or((command, ""));
+        match name.trim_end() {
+            "input" => {
+                self.io.push_input(rest.as_bytes());
+                self.io.push_input(b"\n");
+                String::new()
+            }
+            "eof" => {
+                self.io.close();
+                String::new()
+            }
+            "cycles" => format!("{}\n", self.machine.cycles()),
+            "help" | "" => MONITOR_HELP.to_string(),
+            _ => format!("Unknown monitor command `{}`\n{}", name, MONITOR_HELP),
+        }
+    }
+
+    fn pc(&self) -> u32 {
+        (self.machine.finger() as u32).wrapping_mul(4)
+    }
+
+    /// Set r0-r7, or the finger as register 8 from a byte address.
+    fn set_register(&mut self, register: usize, value: u32) -> Result<(), AccessError> {
+        if register == 8 {
+            self.machine.set_finger((value / 4) as usize);
+            Ok(())
+        } else {
+            self.machine.set_register(register, value)
+        }
+    }
+
+    fn byte(&self, address: u64) -> Option<u8> {
+        let word = self
+            .machine
+            .word((address >> 32) as u32, (address as u32) / 4)?;
+        Some(word.to_be_bytes()[(address % 4) as usize])
+    }
+
+    fn set_byte(&mut self, address: u64, byte: u8) -> bool {
+        let (array, offset) = ((address >> 32) as u32, (address as u32) / 4);
+        match self.machine.word(array, offset) {
+            Some(word) => {
+                let mut bytes = word.to_be_bytes();
+                bytes[(address % 4) as usize] = byte;
+                self.machine
+                    .set_word(array, offset, u32::from_be_bytes(bytes))
+                    .is_ok()
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, kind: u8, address: u64, length: u64) -> Option<()> {
+        if self.points.contains_key(&(kind, address, length)) {
+            return Some(());
+        }
+        let array = (address >> 32) as u32;
+        let first = (address as u32) / 4;
+        // No array reaches past 2^32 bytes
+        let span = u32::try_from(length.max(1)).ok()?;
+        let last = (address as u32).saturating_add(span - 1) / 4;
+        let triggers = match kind {
+            SOFTWARE | HARDWARE if array == 0 => vec![Trigger::Finger(first as usize)],
+            WRITE => vec![Trigger::Write {
+                array,
+                offsets: first..=last,
+            }],
+            READ => vec![Trigger::Read {
+                array,
+                offsets: first..=last,
+            }],
+            ACCESS => vec![
+                Trigger::Write {
+                    array,
+                    offsets: first..=last,
+                },
+                Trigger::Read {
+                    array,
+                    offsets: first..=last,
+                },
+            ],
+            _ => return None,
+        };
+        let breakpoints = triggers.into_iter().map(Breakpoint::new);
+        let breakpoints: Vec<Breakpoint> = breakpoints.collect::<Result<_, _>>().ok()?;
+        let ids = breakpoints
+            .into_iter()
+            .map(|breakpoint| self.machine.add_breakpoint(breakpoint))
+            .collect();
+        self.points.insert((kind, address, length), ids);
+        Some(())
+    }
+
+    /// Whether the client has sent an interrupt, keeping anything else it
+    /// sent for `receive`.
+    fn interrupted(&mut self, conn: &mut dyn Connection) -> io::Result<bool> {
+        while let Some(byte) = conn.poll()? {
+            if byte == INTERRUPT {
+                return Ok(true);
+            }
+            self.received.push_back(byte);
+        }
+        Ok(false)
+    }
+
+    /// Step once, or run until something stops the machine or the client
+    /// interrupts it, and describe why it stopped.
+    fn resume(&mut self, step: bool, conn: &mut dyn Connection) -> io::Result<String> {
+        let outcome = if step {
+            self.machine.run(RunLimit::cycles(1))
+        } else {
+            loop {
+                match self.machine.run(RunLimit::cycles(SLICE)) {
+                    RunOutcome::CycleLimit => {}
+                    outcome => break outcome,
+                }
+                self.forward_output(conn)?;
+                if self.interrupted(conn)? {
+                    self.stop = "S02".to_string();
+                    return Ok(self.stop.clone());
+                }
+            }
+        };
+        let reply = self.stop_reply(outcome, conn)?;
+        self.forward_output(conn)?;
+        self.stop = reply.clone();
+        Ok(reply)
+    }
+
+    fn stop_reply(&mut self, outcome: RunOutcome, conn: &mut dyn Connection) -> io::Result<String> {
+        Ok(match outcome {
+            RunOutcome::Breakpoint(id) => {
+                let kind = self
+                    .points
+                    .iter()
+                    .find(|(_, ids)| ids.contains(&id))
+                    .map(|(&(kind, _, _), _)| kind);
+                match kind {
+                    Some(SOFTWARE) => "T05swbreak:;".to_string(),
+                    Some(HARDWARE) => "T05hwbreak:;".to_string(),
+                    Some(kind) => {
+                        // Watchpoints report the access once it has happened
+                        let address = self.accessed();
+                        match self.machine.run(RunLimit::cycles(1)) {
+                            RunOutcome::CycleLimit => {
+                                let name = match kind {
+                                    WRITE => "watch",
+                                    READ => "rwatch",
+                                    _ => "awatch",
+                                };
+                                format!("T05{}:{:x};", name, address.unwrap_or(0))
+                            }
+                            outcome => return self.stop_reply(outcome, conn),
+                        }
+                    }
+                    None => STOPPED.to_string(),
+                }
+            }
+            RunOutcome::Halted => "W00".to_string(),
+            RunOutcome::Blocked => {
+                self.forward_output(conn)?;
+                self.console(
+                    conn,
+                    b"Waiting for input, use `monitor input TEXT` or `monitor eof`\n",
+                )?;
+                STOPPED.to_string()
+            }
+            RunOutcome::Fault(fault) => {
+                self.forward_output(conn)?;
+                self.console(conn, format!("Machine fault: {}\n", fault).as_bytes())?;
+                "S0b".to_string()
+            }
+            RunOutcome::CycleLimit | RunOutcome::Deadline => STOPPED.to_string(),
+        })
+    }
+
+    /// The address of the word the instruction at the finger reads or
+    /// writes.
+    fn accessed(&self) -> Option<u64> {
+        let r = self.machine.registers();
+        let (array, offset) = match self.machine.instruction().ok()? {
+            Index(Pointers { b, c, .. }) => (r[b], r[c]),
+            Amend(Pointers { a, b, .. }) => (r[a], r[b]),
+            _ => return None,
+        };
+        Some((u64::from(array) << 32) | (u64::from(offset) * 4))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asm::boot;
+
+    /// A client that has already sent everything it will send.
+    struct Script {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Script {
+        fn poll(&mut self) -> io::Result<Option<u8>> {
+            Ok(self.input.pop_front())
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, checksum(data.as_bytes()))
+    }
+
+    /// Serve `packets` without acknowledgements and return the replies.
+    fn session(stub: &mut Stub, packets: &[&str]) -> Vec<String> {
+        let mut input = packet("QStartNoAckMode") + "+";
+        for data in packets {
+            input += &packet(data);
+        }
+        let mut script = Script {
+            input: input.into_bytes().into(),
+            output: Vec::new(),
+        };
+        stub.serve(&mut script).unwrap();
+        let output = String::from_utf8(script.output).unwrap();
+        assert!(output.starts_with(&format!("+{}", packet("OK"))));
+        output
+            .split('$')
+            .skip(2)
+            .map(|p| p.split('#').next().unwrap().to_string())
+            .collect()
+    }
+
+    fn stub(source: &str) -> Stub {
+        let io = QueueIo::new();
+        Stub::new(boot(source, io.clone()), io)
+    }
+
+    #[test]
+    fn test_packets() {
+        let mut stub = stub("halt");
+        let mut script = Script {
+            input: b"$?#3f+$g#00$g#67-+".to_vec().into(),
+            output: Vec::new(),
+        };
+        stub.serve(&mut script).unwrap();
+        // The corrupt packet is refused, and the refused reply sent again
+        let registers = packet(&"00000000".repeat(9));
+        assert_eq!(
+            String::from_utf8(script.output).unwrap(),
+            format!("+{}-+{}{}", packet("S05"), registers, registers)
+        );
+    }
+
+    #[test]
+    fn test_packet_size() {
+        // Reads stop at what fits in a packet
+        let mut stub = stub("halt\n.space 1000");
+        let replies = session(&mut stub, &["qSupported", "m0,ffffffff"]);
+        assert!(replies[0].starts_with("PacketSize=1000;"));
+        assert_eq!(replies[1].len(), 0x1000);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut stub = stub("load r0, r0");
+        // A packet sent while the machine runs is answered after the
+        // interrupt that follows it
+        let input = packet("QStartNoAckMode") + "+" + &packet("c") + &packet("?") + "\u{3}";
+        let mut script = Script {
+            input: input.into_bytes().into(),
+            output: Vec::new(),
+        };
+        stub.serve(&mut script).unwrap();
+        assert_eq!(
+            String::from_utf8(script.output).unwrap(),
+            format!("+{}{}{}", packet("OK"), packet("S02"), packet("S02"))
+        );
+        assert!(stub.machine().cycles() >= SLICE);
+    }
+
+    #[test]
+    fn test_session() {
+        let mut stub = stub(
+            "
+                    ortho r1, 2
+                    alloc r2, r1
+                    ortho r3, 'h'
+                    amend r2, r0, r3
+                    out r3
+                    in r4
+                    out r4
+                    halt
+            ",
+        );
+        let replies = session(
+            &mut stub,
+            &[
+                "Z0,10,4",
+                "Z2,100000000,4",
+                "vCont;c",
+                "p8",
+                "m100000000,8",
+                "z2,100000000,4",
+                "c",
+                "c",
+                "g",
+                &format!("qRcmd,{}", hex(b"input hi")),
+                "s",
+                "Z0,1c,4",
+                "c",
+                "c",
+                "m0,2",
+                "M0,4:70000000",
+                "m0,4",
+                "m900000000,4",
+                "P3=0000002a",
+                "p3",
+                "P9=00000000",
+                "Z2,100000000,100000000",
+                "Z2,100000000,ffffffff",
+            ],
+        );
+        let waiting = hex(b"Waiting for input, use `monitor input TEXT` or `monitor eof`\n");
+        let expected = [
+            "OK",
+            "OK",
+            // The watchpoint stops after the amend, before the breakpoint
+            "T05watch:100000000;",
+            "00000010",
+            "0000006800000000",
+            "OK",
+            // The breakpoint on the instruction after the amend
+            "T05swbreak:;",
+            "O68",
+            &format!("O{}", waiting),
+            "S05",
+            "000000000000000200000001000000680000000000000000000000000000000000000014",
+            "OK",
+            "S05",
+            "OK",
+            "O68",
+            "T05swbreak:;",
+            "W00",
+            "d200",
+            "OK",
+            "70000000",
+            "E01",
+            "OK",
+            "0000002a",
+            "E01",
+            "E01",
+            "OK",
+        ];
+        assert_eq!(replies, expected);
+    }
+}
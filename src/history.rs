@@ -0,0 +1,582 @@
+//! Running a machine backwards.
+//!
+//! With history on, the machine takes a checkpoint every `interval` cycles
+//! and keeps the last few. Checkpoints share arrays with the running
+//! machine until either side amends them, so each costs little more than
+//! the arrays written in its interval. Since the latest checkpoint the
+//! machine also logs, per cycle, what it needs to undo that cycle: the
+//! finger and registers, and the word, array or program it replaced.
+//!
+//! Going back within the current interval pops the undo log. Going back
+//! further restores an older checkpoint and runs forward again to the
+//! cycle wanted, feeding the input logged on the way; the machine is
+//! deterministic otherwise, so it arrives in the same state.
+//!
+//! Input read after the cycle rewound to is handed back through
+//! `UmIo::unread` to be read again. Output cannot be taken back and is not
+//! written a second time when replaying, but is when running forward once
+//! more. Changing registers, words or the finger by hand starts history
+//! afresh.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use crate::arrays::{ArrayStats, Arrays};
+use crate::io::QueueIo;
+use crate::record::InputEvent;
+use crate::Instruction::{self, *};
+use crate::{Machine, Pointers, RunLimit, StepOutcome};
+
+/// How often a machine keeps a checkpoint and how many it keeps. Going
+/// back reaches `interval * (checkpoints - 1)` cycles at least.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct HistoryConfig {
+    pub interval: u64,
+    pub checkpoints: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            interval: 100_000,
+            checkpoints: 32,
+        }
+    }
+}
+
+/// Why the machine could not go back.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HistoryError {
+    /// History is off.
+    Disabled,
+    /// The cycle is before the oldest checkpoint.
+    NotRecorded { cycle: u64, earliest: u64 },
+    /// The cycle has not happened yet.
+    Ahead { cycle: u64 },
+    /// Nothing in history wrote the word looked for.
+    NotFound,
+    /// Running forward from a checkpoint did not reach the cycle, which
+    /// means the machine was changed behind history's back.
+    Diverged { cycle: u64 },
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::HistoryError::*;
+        match self {
+            Disabled => write!(f, "history is not enabled"),
+            NotRecorded { cycle, earliest } => write!(
+                f,
+                "cycle {} is before the earliest kept, {}",
+                cycle, earliest
+            ),
+            Ahead { cycle } => write!(f, "cycle {} has not been reached", cycle),
+            NotFound => write!(f, "no such write in history"),
+            Diverged { cycle } => write!(f, "replay diverged at cycle {}", cycle),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+#[derive(Clone)]
+struct Checkpoint {
+    cycle: u64,
+    fin: usize,
+    reg: [u32; 8],
+    arrays: Arrays,
+    halted: bool,
+    output_bytes: u64,
+}
+
+/// What an instruction changed besides the finger and registers.
+enum Effect {
+    None,
+    Amend {
+        array: u32,
+        offset: u32,
+        old: u32,
+    },
+    Allocate {
+        id: u32,
+        reused: bool,
+        stats: ArrayStats,
+    },
+    Abandon {
+        id: u32,
+        array: Arc<Vec<u32>>,
+        stats: ArrayStats,
+    },
+    Load {
+        program: Arc<Vec<u32>>,
+        stats: ArrayStats,
+    },
+    Out,
+    In(Option<u8>),
+}
+
+pub(crate) struct Undo {
+    finger: usize,
+    registers: [u32; 8],
+    effect: Effect,
+}
+
+pub(crate) struct History {
+    config: HistoryConfig,
+    // Oldest first; the last starts the current interval
+    checkpoints: VecDeque<Checkpoint>,
+    // One per cycle since the last checkpoint
+    undo: Vec<Undo>,
+    // Everything read since the first checkpoint
+    input: Vec<InputEvent>,
+}
+
+impl History {
+    fn new(config: HistoryConfig, checkpoint: Checkpoint) -> Self {
+        History {
+            config,
+            checkpoints: VecDeque::from(vec![checkpoint]),
+            undo: Vec::new(),
+            input: Vec::new(),
+        }
+    }
+
+    fn latest(&self) -> &Checkpoint {
+        self.checkpoints.back().expect("History keeps a checkpoint")
+    }
+}
+
+impl Machine {
+    /// Keep history as `config` asks, or stop keeping it with `None`. Input
+    /// read by cycles that are rewound goes back through `UmIo::unread`.
+    pub fn set_history(&mut self, config: Option<HistoryConfig>) {
+        self.history = config.map(|config| Box::new(History::new(config, self.checkpoint())));
+    }
+
+    /// The earliest cycle the machine can go back to.
+    pub fn history_start(&self) -> Option<u64> {
+        let history = self.history.as_ref()?;
+        history
+            .checkpoints
+            .front()
+            .map(|checkpoint| checkpoint.cycle)
+    }
+
+    /// Undo the last cycle.
+    pub fn step_back(&mut self) -> Result<(), HistoryError> {
+        match self.cycles {
+            0 => Err(HistoryError::NotRecorded {
+                cycle: 0,
+                earliest: 0,
+            }),
+            cycles => self.rewind(cycles - 1),
+        }
+    }
+
+    /// Go back to the state before `cycle` executed.
+    pub fn rewind(&mut self, cycle: u64) -> Result<(), HistoryError> {
+        let history = self.history.as_ref().ok_or(HistoryError::Disabled)?;
+        if cycle > self.cycles {
+            return Err(HistoryError::Ahead { cycle });
+        }
+        if cycle < history.latest().cycle {
+            let index = history
+                .checkpoints
+                .iter()
+                .rposition(|checkpoint| checkpoint.cycle <= cycle)
+                .ok_or(HistoryError::NotRecorded {
+                    cycle,
+                    earliest: history.checkpoints[0].cycle,
+                })?;
+            let checkpoint = &history.checkpoints[index];
+            let mut replay = self.replay_from(checkpoint, cycle);
+            let config = HistoryConfig {
+                interval: u64::MAX,
+                checkpoints: 1,
+            };
+            replay.history = Some(Box::new(History::new(config, checkpoint.clone())));
+            replay.run(RunLimit::cycles(cycle - checkpoint.cycle));
+            if replay.cycles != cycle {
+                return Err(HistoryError::Diverged {
+                    cycle: replay.cycles,
+                });
+            }
+
+            let unread: Vec<u8> = history
+                .input
+                .iter()
+                .filter(|event| event.cycle >= cycle)
+                .filter_map(|event| event.byte)
+                .collect();
+            self.io.unread(&unread);
+            self.fin = replay.fin;
+            self.reg = replay.reg;
+            self.arrays = replay.arrays;
+            self.cycles = replay.cycles;
+            self.halted = replay.halted;
+            self.output_bytes = replay.output_bytes;
+            self.clear_code();
+            let history = self.history.as_mut().unwrap();
+            history.checkpoints.truncate(index + 1);
+            history.undo = replay.history.unwrap().undo;
+            history.input.retain(|event| event.cycle < cycle);
+        }
+        while self.cycles > cycle {
+            self.undo();
+        }
+        Ok(())
+    }
+
+    /// Go back to just before the last `Amend` of `offset` in `array`,
+    /// returning the cycle it executed on.
+    pub fn reverse_to_write(&mut self, array: u32, offset: u32) -> Result<u64, HistoryError> {
+        let history = self.history.as_ref().ok_or(HistoryError::Disabled)?;
+        let is_write = |undo: &Undo| match undo.effect {
+            Effect::Amend {
+                array: a,
+                offset: o,
+                ..
+            } => a == array && o == offset,
+            _ => false,
+        };
+        let start = history.latest().cycle;
+        let mut found = history
+            .undo
+            .iter()
+            .rposition(is_write)
+            .map(|index| start + index as u64);
+        // Earlier intervals have no undo log, so run through them again
+        for index in (0..history.checkpoints.len() - 1).rev() {
+            if found.is_some() {
+                break;
+            }
+            let end = history.checkpoints[index + 1].cycle;
+            let mut replay = self.replay_from(&history.checkpoints[index], end);
+            while replay.cycles < end {
+                if let Ok(Amend(Pointers { a, b, .. })) = replay.instruction() {
+                    if replay.reg[a] == array && replay.reg[b] == offset {
+                        found = Some(replay.cycles);
+                    }
+                }
+                if replay.step() != Ok(StepOutcome::Running) {
+                    break;
+                }
+            }
+        }
+        let cycle = found.ok_or(HistoryError::NotFound)?;
+        self.rewind(cycle)?;
+        Ok(cycle)
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            cycle: self.cycles,
+            fin: self.fin,
+            reg: self.reg,
+            arrays: self.arrays.clone(),
+            halted: self.halted,
+            output_bytes: self.output_bytes,
+        }
+    }
+
+    /// Forget history after a change it cannot account for.
+    pub(crate) fn restart_history(&mut self) {
+        if let Some(history) = &self.history {
+            let config = history.config;
+            self.set_history(Some(config));
+        }
+    }
+
+    /// A machine in the state of `checkpoint`, with the input logged up to
+    /// `end` queued to be read.
+    fn replay_from(&self, checkpoint: &Checkpoint, end: u64) -> Machine {
+        let io = QueueIo::new();
+        let history = self.history.as_ref().unwrap();
+        let input = history
+            .input
+            .iter()
+            .filter(|event| (checkpoint.cycle..end).contains(&event.cycle));
+        for event in input {
+            match event.byte {
+                Some(byte) => io.push_input(&[byte]),
+                None => {
+                    io.close();
+                    break;
+                }
+            }
+        }
+        let mut machine = Machine::with_eof_policy(io, &mut io::empty(), self.eof);
+        machine.fin = checkpoint.fin;
+        machine.reg = checkpoint.reg;
+        machine.arrays = checkpoint.arrays.clone();
+        machine.cycles = checkpoint.cycle;
+        machine.halted = checkpoint.halted;
+        machine.output_bytes = checkpoint.output_bytes;
+        machine
+    }
+
+    /// What undoing `instruction` will need, noted before it executes.
+    pub(crate) fn prepare_undo(&self, finger: usize, instruction: Instruction) -> Undo {
+        let reg = &self.reg;
+        let stats = self.arrays.stats;
+        let effect = match instruction {
+            Amend(Pointers { a, b, .. }) => match self.word(reg[a], reg[b]) {
+                Some(old) => Effect::Amend {
+                    array: reg[a],
+                    offset: reg[b],
+                    old,
+                },
+                None => Effect::None,
+            },
+            Allocate(_) => Effect::Allocate {
+                id: 0,
+                reused: !self.arrays.free().is_empty(),
+                stats,
+            },
+            Abandon(Pointers { c, .. }) => match self.arrays.get(reg[c]) {
+                Some(array) => Effect::Abandon {
+                    id: reg[c],
+                    array: Arc::clone(array),
+                    stats,
+                },
+                None => Effect::None,
+            },
+            Load(Pointers { b, .. }) if reg[b] != 0 => Effect::Load {
+                program: Arc::clone(self.arrays.program()),
+                stats,
+            },
+            Out(_) => Effect::Out,
+            In(_) => Effect::In(None),
+            _ => Effect::None,
+        };
+        Undo {
+            finger,
+            registers: self.reg,
+            effect,
+        }
+    }
+
+    /// Log a cycle that has executed `instruction`, taking a checkpoint
+    /// when one is due.
+    pub(crate) fn push_undo(&mut self, mut undo: Undo, instruction: Instruction) {
+        let cycle = self.cycles - 1;
+        let history = self.history.as_mut().unwrap();
+        match (&mut undo.effect, instruction) {
+            (Effect::Allocate { id, .. }, Allocate(Pointers { b, .. })) => *id = self.reg[b],
+            (Effect::In(byte), In(Pointers { c, .. })) => {
+                // Only the end of input leaves more than a byte
+                *byte = u8::try_from(self.reg[c]).ok();
+                history.input.push(InputEvent { cycle, byte: *byte });
+            }
+            _ => {}
+        }
+        history.undo.push(undo);
+        if self.cycles - history.latest().cycle < history.config.interval {
+            return;
+        }
+        let checkpoint = self.checkpoint();
+        let history = self.history.as_mut().unwrap();
+        history.checkpoints.push_back(checkpoint);
+        history.undo.clear();
+        if history.checkpoints.len() > history.config.checkpoints.max(1) {
+            history.checkpoints.pop_front();
+            let earliest = history.checkpoints[0].cycle;
+            history.input.retain(|event| event.cycle >= earliest);
+        }
+    }
+
+    /// Take back the last cycle of the undo log.
+    fn undo(&mut self) {
+        let history = self.history.as_mut().unwrap();
+        let undo = history.undo.pop().expect("Undo log covers the interval");
+        if let Effect::In(_) = undo.effect {
+            history.input.pop();
+        }
+        self.fin = undo.finger;
+        self.reg = undo.registers;
+        self.cycles -= 1;
+        self.halted = false;
+        match undo.effect {
+            Effect::None => {}
+            Effect::Amend { array, offset, old } => {
+                let words = self.arrays.get_mut(array).expect("Amended array is live");
+                Arc::make_mut(words)[offset as usize] = old;
+                if array == 0 {
+                    self.invalidate_code(offset as usize);
+                }
+            }
+            Effect::Allocate { id, reused, stats } => self.arrays.unallocate(id, reused, stats),
+            Effect::Abandon { id, array, stats } => self.arrays.unabandon(id, array, stats),
+            Effect::Load { program, stats } => {
+                self.arrays.set_program(program);
+                self.arrays.stats = stats;
+                self.clear_code();
+            }
+            Effect::Out => self.output_bytes -= 1,
+            Effect::In(byte) => {
+                if let Some(byte) = byte {
+                    self.io.unread(&[byte]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asm;
+    use crate::io::StreamIo;
+    use crate::RunOutcome;
+
+    // Echo, keeping the last byte in a one word array and a sum in r7
+    const ECHO: &str = "
+                ortho r1, 1
+                alloc r2, r1
+                ortho r6, loop
+        loop:   in r4
+                amend r2, r0, r4
+                add r7, r7, r4
+                out r4
+                load r0, r6
+    ";
+
+    // Allocates, abandons, amends itself and loads a zeroed array, whose
+    // `cmov r0, r0, r0`s run off its end
+    const ARRAYS: &str = "
+                ortho r1, 2
+                alloc r2, r1
+                alloc r3, r1
+                abandon r2
+                alloc r2, r1
+                ortho r4, 9
+                ortho r5, data
+                amend r0, r5, r4
+                out r1
+                load r3, r6
+                halt
+        data:   .word 0
+    ";
+
+    type State = (u64, usize, [u32; 8], Vec<(u32, Vec<u32>)>, ArrayStats);
+
+    fn boot(source: &str, history: bool) -> (Machine, QueueIo) {
+        let io = QueueIo::new();
+        let mut machine = asm::boot(source, io.clone());
+        if history {
+            machine.set_history(Some(HistoryConfig {
+                interval: 8,
+                checkpoints: 3,
+            }));
+        }
+        io.push_input(b"abcdef");
+        (machine, io)
+    }
+
+    fn state(machine: &Machine) -> State {
+        let arrays = machine
+            .live_arrays()
+            .map(|(id, _)| (id, machine.array(id).unwrap().to_vec()))
+            .collect();
+        (
+            machine.cycles(),
+            machine.finger(),
+            machine.registers(),
+            arrays,
+            machine.array_stats(),
+        )
+    }
+
+    /// The state of a machine without history after `cycles`.
+    fn reference(source: &str, cycles: u64) -> State {
+        let (mut machine, _) = boot(source, false);
+        machine.run(RunLimit::cycles(cycles));
+        state(&machine)
+    }
+
+    #[test]
+    fn test_rewind() {
+        let (mut machine, io) = boot(ECHO, true);
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Blocked);
+        assert_eq!(io.drain_output(), b"abcdef");
+        assert_eq!(machine.cycles(), 33);
+        assert_eq!(machine.history_start(), Some(16));
+
+        machine.step_back().unwrap();
+        assert_eq!(state(&machine), reference(ECHO, 32));
+        // Back past the last checkpoint, between reading the "d" and
+        // writing it, handing back "ef"
+        machine.rewind(20).unwrap();
+        assert_eq!(state(&machine), reference(ECHO, 20));
+        assert_eq!(
+            machine.rewind(10),
+            Err(HistoryError::NotRecorded {
+                cycle: 10,
+                earliest: 16
+            })
+        );
+        assert_eq!(machine.rewind(30), Err(HistoryError::Ahead { cycle: 30 }));
+
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Blocked);
+        assert_eq!(state(&machine), reference(ECHO, 33));
+        assert_eq!(io.drain_output(), b"def");
+    }
+
+    #[test]
+    fn test_rewind_stream() {
+        let io = StreamIo::new(&b"abc"[..], io::sink());
+        let mut machine = asm::boot(ECHO, io);
+        machine.set_history(Some(HistoryConfig {
+            interval: 8,
+            checkpoints: 3,
+        }));
+        // End of input reads all ones, which `out` faults on
+        let fault = machine.run(RunLimit::default());
+        assert!(matches!(fault, RunOutcome::Fault(_)));
+        let sum = machine.registers()[7];
+
+        // Back to just after reading "b", past a checkpoint, then into the
+        // undo log after reading "c" again
+        machine.rewind(9).unwrap();
+        assert_eq!(machine.registers()[4], u32::from(b'b'));
+        assert_eq!(machine.run(RunLimit::cycles(6)), RunOutcome::CycleLimit);
+        machine.rewind(14).unwrap();
+        assert_eq!(machine.registers()[4], u32::from(b'c'));
+        assert_eq!(machine.run(RunLimit::default()), fault);
+        assert_eq!(machine.registers()[7], sum);
+    }
+
+    #[test]
+    fn test_reverse_to_write() {
+        let (mut machine, _) = boot(ECHO, true);
+        machine.run(RunLimit::default());
+        // The amends run on cycles 4, 9, ..., 29
+        assert_eq!(machine.reverse_to_write(1, 0), Ok(29));
+        assert_eq!(state(&machine), reference(ECHO, 29));
+        assert_eq!(machine.reverse_to_write(1, 0), Ok(24));
+        // Found by running the interval from cycle 16 again
+        assert_eq!(machine.reverse_to_write(1, 0), Ok(19));
+        assert_eq!(state(&machine), reference(ECHO, 19));
+        assert_eq!(machine.word(1, 0), Some(u32::from(b'c')));
+        assert_eq!(machine.reverse_to_write(1, 5), Err(HistoryError::NotFound));
+    }
+
+    #[test]
+    fn test_step_back() {
+        let (mut machine, _) = boot(ARRAYS, true);
+        assert!(matches!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Fault(_)
+        ));
+        assert_eq!(machine.cycles(), 12);
+        assert_eq!(state(&machine), reference(ARRAYS, 12));
+        for cycle in (0..12).rev() {
+            machine.step_back().unwrap();
+            assert_eq!(state(&machine), reference(ARRAYS, cycle));
+        }
+    }
+}
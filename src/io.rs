@@ -0,0 +1,366 @@
+//! Input and output for a `Machine`.
+//!
+//! The machine only ever asks for one byte at a time and hands one byte out
+//! at a time, so anything from a terminal to a test harness can sit behind
+//! `UmIo`. Whether `In` blocks is up to the implementation: a `WouldBlock`
+//! leaves the machine parked on the instruction until the next `run`.
+//! Implementations are `Send`, so a machine can be handed to another thread.
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Input {
+    Byte(u8),
+    WouldBlock,
+    Eof,
+    /// Reading failed; the machine stops with `Fault::InputFailed`.
+    Error(ErrorKind),
+}
+
+pub trait UmIo: Send {
+    fn read_byte(&mut self) -> Input;
+    fn write_byte(&mut self, byte: u8) -> io::Result<()>;
+
+    /// Input received but not yet read by the machine, for a snapshot.
+    /// Implementations that cannot look ahead report nothing.
+    fn pending_input(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Output written but not yet collected by the host, for a snapshot.
+    fn buffered_output(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Put `input` back ahead of anything not yet read, to be read again in
+    /// order. History hands back the input read by cycles it rewinds over.
+    /// Implementations that cannot take input back lose it.
+    fn unread(&mut self, _input: &[u8]) {}
+
+    /// Take back what `pending_input` and `buffered_output` reported when
+    /// a machine is restored from a snapshot.
+    fn restore(&mut self, pending_input: &[u8], _buffered_output: &[u8]) {
+        self.unread(pending_input);
+    }
+}
+
+/// Queue `input` ahead of what `queue` holds, for implementing `unread`.
+pub fn push_front(queue: &mut VecDeque<u8>, input: &[u8]) {
+    for &byte in input.iter().rev() {
+        queue.push_front(byte);
+    }
+}
+
+/// Blocking I/O over any reader and writer, e.g. stdin and stdout.
+pub struct StreamIo<R, W> {
+    reader: R,
+    writer: W,
+    // Input given back by `unread`, read before the reader
+    unread: VecDeque<u8>,
+}
+
+impl<R: Read, W: Write> StreamIo<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        StreamIo {
+            reader,
+            writer,
+            unread: VecDeque::new(),
+        }
+    }
+}
+
+impl<R: Read + Send, W: Write + Send> UmIo for StreamIo<R, W> {
+    fn read_byte(&mut self) -> Input {
+        if let Some(byte) = self.unread.pop_front() {
+            return Input::Byte(byte);
+        }
+        // Prompts rarely end in a newline, make sure they are visible
+        // before we wait on the user
+        let _ = self.writer.flush();
+        let mut byte = [0];
+        loop {
+            return match self.reader.read(&mut byte) {
+                Ok(0) => Input::Eof,
+                Ok(_) => Input::Byte(byte[0]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => Input::WouldBlock,
+                Err(e) => Input::Error(e.kind()),
+            };
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.writer.write_all(&[byte])
+    }
+
+    fn unread(&mut self, input: &[u8]) {
+        push_front(&mut self.unread, input);
+    }
+}
+
+/// Blocking I/O over a pair of channels. Input ends when every sender
+/// has been dropped.
+pub struct ChannelIo {
+    inbox: Receiver<u8>,
+    outbox: Sender<u8>,
+    // Input taken off the channel for a snapshot or given back by `unread`
+    pending: VecDeque<u8>,
+}
+
+impl ChannelIo {
+    pub fn new(inbox: Receiver<u8>, outbox: Sender<u8>) -> Self {
+        ChannelIo {
+            inbox,
+            outbox,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl UmIo for ChannelIo {
+    fn read_byte(&mut self) -> Input {
+        if let Some(byte) = self.pending.pop_front() {
+            return Input::Byte(byte);
+        }
+        self.inbox.recv().map_or(Input::Eof, Input::Byte)
+    }
+
+    fn pending_input(&mut self) -> Vec<u8> {
+        self.pending.extend(self.inbox.try_iter());
+        self.pending.iter().cloned().collect()
+    }
+
+    fn unread(&mut self, input: &[u8]) {
+        push_front(&mut self.pending, input);
+    }
+
+    fn restore(&mut self, pending_input: &[u8], buffered_output: &[u8]) {
+        self.unread(pending_input);
+        for &byte in buffered_output {
+            let _ = self.outbox.send(byte);
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.outbox
+            .send(byte)
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "Output channel closed"))
+    }
+}
+
+#[derive(Default)]
+struct Buffers {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    closed: bool,
+}
+
+impl Buffers {
+    fn read_byte(&mut self) -> Input {
+        match self.input.pop_front() {
+            Some(b) => Input::Byte(b),
+            None if self.closed => Input::Eof,
+            None => Input::WouldBlock,
+        }
+    }
+
+    fn restore(&mut self, pending_input: &[u8], buffered_output: &[u8]) {
+        // Anything queued since belongs after what the machine had pending
+        push_front(&mut self.input, pending_input);
+        self.output.splice(0..0, buffered_output.iter().cloned());
+    }
+}
+
+/// Fixed in-memory input, collected output. Clones share their buffers so
+/// a harness can keep one to read the output while the machine owns another.
+#[derive(Clone, Default)]
+pub struct BufferIo {
+    buffers: Arc<Mutex<Buffers>>,
+}
+
+impl BufferIo {
+    pub fn new(input: &[u8]) -> Self {
+        let buffers = Buffers {
+            input: input.iter().cloned().collect(),
+            output: Vec::new(),
+            closed: true,
+        };
+        BufferIo {
+            buffers: Arc::new(Mutex::new(buffers)),
+        }
+    }
+
+    fn buffers(&self) -> MutexGuard<'_, Buffers> {
+        self.buffers.lock().unwrap()
+    }
+
+    pub fn output(&self) -> Vec<u8> {
+        self.buffers().output.clone()
+    }
+}
+
+impl UmIo for BufferIo {
+    fn read_byte(&mut self) -> Input {
+        self.buffers().read_byte()
+    }
+
+    fn unread(&mut self, input: &[u8]) {
+        push_front(&mut self.buffers().input, input);
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.buffers().output.push(byte);
+        Ok(())
+    }
+
+    fn pending_input(&mut self) -> Vec<u8> {
+        self.buffers().input.iter().cloned().collect()
+    }
+
+    fn buffered_output(&mut self) -> Vec<u8> {
+        self.buffers().output.clone()
+    }
+
+    fn restore(&mut self, pending_input: &[u8], buffered_output: &[u8]) {
+        self.buffers().restore(pending_input, buffered_output);
+    }
+}
+
+/// Non-blocking I/O for a host that feeds the machine between runs: `In`
+/// reports `WouldBlock` on an empty queue until the queue is closed.
+/// Clones share their queues.
+#[derive(Clone, Default)]
+pub struct QueueIo {
+    buffers: Arc<Mutex<Buffers>>,
+}
+
+impl QueueIo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn buffers(&self) -> MutexGuard<'_, Buffers> {
+        self.buffers.lock().unwrap()
+    }
+
+    pub fn push_input(&self, input: &[u8]) {
+        self.buffers().input.extend(input);
+    }
+
+    /// How many pushed bytes the machine has yet to read.
+    pub fn queued_input(&self) -> usize {
+        self.buffers().input.len()
+    }
+
+    /// Any input still queued is read before the machine sees EOF.
+    pub fn close(&self) {
+        self.buffers().closed = true;
+    }
+
+    pub fn drain_output(&self) -> Vec<u8> {
+        self.buffers().output.drain(..).collect()
+    }
+}
+
+impl UmIo for QueueIo {
+    fn read_byte(&mut self) -> Input {
+        self.buffers().read_byte()
+    }
+
+    fn unread(&mut self, input: &[u8]) {
+        push_front(&mut self.buffers().input, input);
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.buffers().output.push(byte);
+        Ok(())
+    }
+
+    fn pending_input(&mut self) -> Vec<u8> {
+        self.buffers().input.iter().cloned().collect()
+    }
+
+    fn buffered_output(&mut self) -> Vec<u8> {
+        self.buffers().output.clone()
+    }
+
+    fn restore(&mut self, pending_input: &[u8], buffered_output: &[u8]) {
+        self.buffers().restore(pending_input, buffered_output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_queue() {
+        let io = QueueIo::new();
+        let mut machine_side = io.clone();
+        assert_eq!(machine_side.read_byte(), Input::WouldBlock);
+        io.push_input(b"a");
+        assert_eq!(machine_side.read_byte(), Input::Byte(b'a'));
+        machine_side.write_byte(b'b').unwrap();
+        assert_eq!(io.drain_output(), b"b");
+        io.close();
+        assert_eq!(machine_side.read_byte(), Input::Eof);
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut io = StreamIo::new(&b"xz"[..], Vec::new());
+        assert_eq!(io.read_byte(), Input::Byte(b'x'));
+        io.unread(b"wx");
+        assert_eq!(io.read_byte(), Input::Byte(b'w'));
+        assert_eq!(io.read_byte(), Input::Byte(b'x'));
+        assert_eq!(io.read_byte(), Input::Byte(b'z'));
+        assert_eq!(io.read_byte(), Input::Eof);
+        io.write_byte(b'y').unwrap();
+        assert_eq!(io.writer, b"y");
+    }
+
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("Input/output error"))
+        }
+    }
+
+    #[test]
+    fn test_stream_error() {
+        let mut io = StreamIo::new(Broken, Vec::new());
+        assert_eq!(io.read_byte(), Input::Error(ErrorKind::Other));
+    }
+
+    #[test]
+    fn test_channel_unread() {
+        let (sender, inbox) = channel();
+        let (outbox, _) = channel();
+        let mut io = ChannelIo::new(inbox, outbox);
+        for &byte in b"abc" {
+            sender.send(byte).unwrap();
+        }
+        assert_eq!(io.read_byte(), Input::Byte(b'a'));
+        assert_eq!(io.read_byte(), Input::Byte(b'b'));
+        // Given back ahead of input already waiting on the channel
+        io.unread(b"ab");
+        assert_eq!(io.pending_input(), b"abc");
+        drop(sender);
+        let read: Vec<Input> = (0..4).map(|_| io.read_byte()).collect();
+        assert_eq!(
+            read,
+            [
+                Input::Byte(b'a'),
+                Input::Byte(b'b'),
+                Input::Byte(b'c'),
+                Input::Eof
+            ]
+        );
+    }
+}
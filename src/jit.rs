@@ -0,0 +1,875 @@
+//! Native x86-64 compilation of hot regions of array 0.
+//!
+//! Every offset the interpreter executes is counted; once one has run
+//! `HOT_THRESHOLD` times the straight run of instructions starting there
+//! is compiled, ending after the next `Load` or before the next `Halt`, `In`
+//! or undecodable platter. Arithmetic is emitted inline against the
+//! register file, everything touching arrays or output calls back into the
+//! interpreter. A `Load` of array 0 back to the start of its own region
+//! loops natively for as long as whole passes fit in the cycle budget.
+//!
+//! A compiled region never raises a fault itself: it returns just before
+//! the offending instruction and lets `Machine::step` report it. It also
+//! returns as soon as a call back amends array 0 or loads a new program,
+//! dropping the regions that were invalidated (deoptimisation), after which
+//! the interpreter carries on until the new code gets hot again.
+//!
+//! `lockstep` runs a compiled and an interpreted machine side by side and
+//! reports the first point at which they disagree.
+
+use std::fmt;
+use std::ptr;
+use std::sync::Arc;
+
+use crate::io::BufferIo;
+use crate::Instruction::*;
+use crate::{Instruction, Machine, OrthoPointers, Pointers, RunLimit, StepOutcome};
+
+const HOT_THRESHOLD: u16 = 16;
+const CHUNK_SIZE: usize = 1 << 16;
+// Code is only reclaimed on a `Load`, so a region amended over and over is
+// left to the interpreter after this many compilations
+const MAX_COMPILES: u8 = 8;
+
+// fn(machine, registers, finger, budget) -> instructions executed
+type Entry = unsafe extern "sysv64" fn(*mut Machine, *mut u32, *mut usize, u64) -> u64;
+
+/// How much compilation the JIT has done.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct JitStats {
+    pub regions_compiled: u64,
+    pub regions_invalidated: u64,
+    pub instructions_compiled: u64,
+    pub code_bytes: u64,
+}
+
+struct Chunk {
+    ptr: *mut u8,
+    size: usize,
+    used: usize,
+}
+
+impl Chunk {
+    fn new(size: usize) -> Self {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert!(ptr != libc::MAP_FAILED, "Could not map JIT memory");
+        Chunk {
+            ptr: ptr as *mut u8,
+            size,
+            used: 0,
+        }
+    }
+
+    fn protect(&self, prot: libc::c_int) {
+        let result = unsafe { libc::mprotect(self.ptr as *mut libc::c_void, self.size, prot) };
+        assert_eq!(result, 0, "Could not change JIT memory protection");
+    }
+}
+
+// The mapping belongs to the chunk alone and is only written through
+// `Jit::install`, which needs `&mut Jit`
+unsafe impl Send for Chunk {}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+struct Region {
+    start: usize,
+    len: usize,
+    entry: Entry,
+}
+
+impl Region {
+    fn covers(&self, offset: usize) -> bool {
+        offset >= self.start && offset < self.start + self.len
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Jit {
+    pub(crate) enabled: bool,
+    heat: Vec<u16>,
+    // Indexed by the offset each region starts at
+    regions: Vec<Option<Arc<Region>>>,
+    // Indexed by offset, the starts of regions that cover it; entries for
+    // regions since invalidated are skipped
+    owners: Vec<Vec<usize>>,
+    // Indexed by start offset, how often a region there has been compiled
+    compiles: Vec<u8>,
+    chunks: Vec<Chunk>,
+    // Chunks dropped while their code may still be on the stack
+    retired: Vec<Chunk>,
+    generation: u64,
+    // Compile each instruction as a region of its own the first time it
+    // runs, for `lockstep` to compare the machines after every instruction
+    single: bool,
+    pub(crate) stats: JitStats,
+}
+
+impl Jit {
+    pub(crate) fn invalidate(&mut self, offset: usize) {
+        let owners = match self.owners.get_mut(offset) {
+            Some(owners) if !owners.is_empty() => std::mem::take(owners),
+            _ => return,
+        };
+        let mut removed = 0;
+        for start in owners {
+            let slot = &mut self.regions[start];
+            if slot.as_ref().is_some_and(|region| region.covers(offset)) {
+                *slot = None;
+                // Wait for the new code to get hot before compiling it
+                self.heat[start] = 0;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            self.stats.regions_invalidated += removed;
+            self.generation += 1;
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.stats.regions_invalidated += self.regions.iter().flatten().count() as u64;
+        self.regions.clear();
+        self.owners.clear();
+        self.compiles.clear();
+        self.heat.clear();
+        self.retired.append(&mut self.chunks);
+        self.generation += 1;
+    }
+
+    fn install(&mut self, code: &[u8]) -> Entry {
+        match self.chunks.last() {
+            Some(chunk) if chunk.size - chunk.used >= code.len() => {}
+            _ => self.chunks.push(Chunk::new(code.len().max(CHUNK_SIZE))),
+        }
+        let chunk = self.chunks.last_mut().unwrap();
+        chunk.protect(libc::PROT_READ | libc::PROT_WRITE);
+        let entry = unsafe {
+            let at = chunk.ptr.add(chunk.used);
+            ptr::copy_nonoverlapping(code.as_ptr(), at, code.len());
+            std::mem::transmute::<*mut u8, Entry>(at)
+        };
+        chunk.protect(libc::PROT_READ | libc::PROT_EXEC);
+        chunk.used += code.len();
+        self.stats.code_bytes += code.len() as u64;
+        entry
+    }
+
+    fn compile(&mut self, program: &[u32], start: usize) -> Option<Arc<Region>> {
+        let mut assembler = Assembler::default();
+        let mut len = 0;
+        let mut jumps = false;
+        assembler.prologue();
+        for (offset, &word) in program.iter().enumerate().skip(start) {
+            match Instruction::decode(word) {
+                None | Some(Halt(_)) | Some(In(_)) => break,
+                Some(instruction) => {
+                    assembler.instruction(instruction, word, start, offset, len as u32);
+                    len += 1;
+                    if let Load(_) = instruction {
+                        jumps = true;
+                        break;
+                    }
+                    if self.single {
+                        break;
+                    }
+                }
+            }
+        }
+        if len == 0 {
+            return None;
+        }
+        if jumps {
+            // Only reached once a call back has moved the finger
+            assembler.exit_placed(len as u32);
+        } else {
+            assembler.exit(len as u32, start + len);
+        }
+
+        let region = Arc::new(Region {
+            start,
+            len,
+            entry: self.install(&assembler.code),
+        });
+        if self.regions.len() <= start {
+            self.regions.resize(start + 1, None);
+        }
+        self.regions[start] = Some(Arc::clone(&region));
+        if self.owners.len() < start + len {
+            self.owners.resize(start + len, Vec::new());
+        }
+        for owners in &mut self.owners[start..start + len] {
+            if !owners.contains(&start) {
+                owners.push(start);
+            }
+        }
+        if self.compiles.len() <= start {
+            self.compiles.resize(start + 1, 0);
+        }
+        self.compiles[start] += 1;
+        self.stats.regions_compiled += 1;
+        self.stats.instructions_compiled += len as u64;
+        Some(region)
+    }
+}
+
+/// Emits x86-64 with the register file addressed through rbx, the machine
+/// kept in r12 for calls back into the interpreter, the finger in r13, the
+/// budget in r14 and the instructions executed by earlier passes round a
+/// self-loop in r15.
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+    // Where each pass round the region starts
+    body: usize,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, value: u32) {
+        self.emit(&value.to_le_bytes());
+    }
+
+    /// Emit a short jump and return where its displacement goes.
+    fn jump8(&mut self, opcode: u8) -> usize {
+        self.emit(&[opcode, 0]);
+        self.code.len() - 1
+    }
+
+    /// Point the short jump at `at` to the current position.
+    fn land(&mut self, at: usize) {
+        let distance = self.code.len() - (at + 1);
+        assert!(distance < 0x80, "JIT short jump out of range");
+        self.code[at] = distance as u8;
+    }
+
+    fn prologue(&mut self) {
+        // push rbx; push r12; push r13; push r14; push r15 (keeps calls
+        // 16-byte aligned)
+        self.emit(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+        // mov rbx, rsi; mov r12, rdi; mov r13, rdx; mov r14, rcx
+        self.emit(&[
+            0x48, 0x89, 0xF3, 0x49, 0x89, 0xFC, 0x49, 0x89, 0xD5, 0x49, 0x89, 0xCE,
+        ]);
+        // xor r15d, r15d
+        self.emit(&[0x45, 0x31, 0xFF]);
+        self.body = self.code.len();
+    }
+
+    /// Return r15 + rax.
+    fn epilogue(&mut self) {
+        // add rax, r15; pop r15; pop r14; pop r13; pop r12; pop rbx; ret
+        self.emit(&[0x4C, 0x01, 0xF8]);
+        self.emit(&[0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5B, 0xC3]);
+    }
+
+    /// Return having executed `executed` instructions of this pass, with
+    /// the finger already placed.
+    fn exit_placed(&mut self, executed: u32) {
+        // mov eax, executed
+        self.emit(&[0xB8]);
+        self.imm32(executed);
+        self.epilogue();
+    }
+
+    /// Store rax as the finger.
+    fn store_finger(&mut self) {
+        // mov [r13], rax
+        self.emit(&[0x49, 0x89, 0x45, 0x00]);
+    }
+
+    /// Return having executed `executed` instructions of this pass, with
+    /// the finger on `finger`.
+    fn exit(&mut self, executed: u32, finger: usize) {
+        // mov eax, finger
+        self.emit(&[0xB8]);
+        self.imm32(finger as u32);
+        self.store_finger();
+        self.exit_placed(executed);
+    }
+
+    fn load(&mut self, opcode: &[u8], register: usize) {
+        self.emit(opcode);
+        self.emit(&[(register * 4) as u8]);
+    }
+
+    fn instruction(
+        &mut self,
+        instruction: Instruction,
+        word: u32,
+        start: usize,
+        finger: usize,
+        index: u32,
+    ) {
+        // [rbx + disp8] operands: eax is 0x43, ecx is 0x4B
+        const MOV_EAX: &[u8] = &[0x8B, 0x43];
+        const MOV_ECX: &[u8] = &[0x8B, 0x4B];
+        const STORE_EAX: &[u8] = &[0x89, 0x43];
+        match instruction {
+            Move(Pointers { a, b, c }) => {
+                self.load(MOV_ECX, c);
+                // test ecx, ecx; jz over the move
+                self.emit(&[0x85, 0xC9, 0x74, 0x06]);
+                self.load(MOV_EAX, b);
+                self.load(STORE_EAX, a);
+            }
+            Add(Pointers { a, b, c }) => {
+                self.load(MOV_EAX, b);
+                self.load(&[0x03, 0x43], c);
+                self.load(STORE_EAX, a);
+            }
+            Mul(Pointers { a, b, c }) => {
+                self.load(MOV_EAX, b);
+                self.load(&[0x0F, 0xAF, 0x43], c);
+                self.load(STORE_EAX, a);
+            }
+            Div(Pointers { a, b, c }) => {
+                self.load(MOV_ECX, c);
+                // test ecx, ecx; jnz over the exit
+                self.emit(&[0x85, 0xC9]);
+                let divisor = self.jump8(0x75);
+                self.exit(index, finger);
+                self.land(divisor);
+                self.load(MOV_EAX, b);
+                // xor edx, edx; div ecx
+                self.emit(&[0x31, 0xD2, 0xF7, 0xF1]);
+                self.load(STORE_EAX, a);
+            }
+            Nand(Pointers { a, b, c }) => {
+                self.load(MOV_EAX, b);
+                self.load(&[0x23, 0x43], c);
+                // not eax
+                self.emit(&[0xF7, 0xD0]);
+                self.load(STORE_EAX, a);
+            }
+            Ortho(OrthoPointers { a, value }) => {
+                self.load(&[0xC7, 0x43], a);
+                self.imm32(value);
+            }
+            Load(Pointers { b, c, .. }) => {
+                // Jumping within the current program needs no interpreter
+                self.load(MOV_EAX, b);
+                // test eax, eax; jnz to the call back
+                self.emit(&[0x85, 0xC0]);
+                let elsewhere = self.jump8(0x75);
+                self.load(MOV_EAX, c);
+                // cmp eax, start; jne to the exit
+                self.emit(&[0x3D]);
+                self.imm32(start as u32);
+                let away = self.jump8(0x75);
+                // Round again if another whole pass fits in the budget:
+                // mov rax, r15; add rax, 2 * len; cmp rax, r14; ja to the exit
+                self.emit(&[0x4C, 0x89, 0xF8, 0x48, 0x05]);
+                self.imm32(2 * (index + 1));
+                self.emit(&[0x4C, 0x39, 0xF0]);
+                let spent = self.jump8(0x77);
+                // sub rax, len; mov r15, rax; jmp body
+                self.emit(&[0x48, 0x2D]);
+                self.imm32(index + 1);
+                self.emit(&[0x49, 0x89, 0xC7, 0xE9]);
+                let back = self.body as i64 - (self.code.len() as i64 + 4);
+                self.imm32(back as i32 as u32);
+                self.land(spent);
+                // mov eax, start
+                self.emit(&[0xB8]);
+                self.imm32(start as u32);
+                self.land(away);
+                self.store_finger();
+                self.exit_placed(index + 1);
+                self.land(elsewhere);
+                self.call_back(word, finger, index);
+            }
+            _ => self.call_back(word, finger, index),
+        }
+    }
+
+    fn call_back(&mut self, word: u32, finger: usize, index: u32) {
+        // mov rdi, r12; mov esi, word; mov edx, finger; mov ecx, index
+        self.emit(&[0x4C, 0x89, 0xE7, 0xBE]);
+        self.imm32(word);
+        self.emit(&[0xBA]);
+        self.imm32(finger as u32);
+        self.emit(&[0xB9]);
+        self.imm32(index);
+        // mov rax, call_back; call rax
+        self.emit(&[0x48, 0xB8]);
+        self.emit(&(call_back as *const () as u64).to_le_bytes());
+        self.emit(&[0xFF, 0xD0]);
+        // test rax, rax; jz over the return; dec rax
+        self.emit(&[0x48, 0x85, 0xC0]);
+        let carry_on = self.jump8(0x74);
+        self.emit(&[0x48, 0xFF, 0xC8]);
+        self.epilogue();
+        self.land(carry_on);
+    }
+}
+
+/// Returns 0 to carry on, otherwise one more than the number of
+/// instructions this pass should report as executed, with the finger
+/// already placed.
+unsafe extern "sysv64" fn call_back(
+    machine: *mut Machine,
+    word: u32,
+    finger: u64,
+    index: u64,
+) -> u64 {
+    let machine = &mut *machine;
+    let instruction = match Instruction::decode(word) {
+        Some(instruction) => instruction,
+        None => {
+            machine.fin = finger as usize;
+            return index + 1;
+        }
+    };
+    let generation = machine.jit.generation;
+    machine.fin = finger as usize + 1;
+    match machine.execute(instruction) {
+        // Leave the fault for the interpreter to raise
+        Err(_) => {
+            machine.fin = finger as usize;
+            index + 1
+        }
+        Ok(_) if machine.jit.generation != generation => index + 2,
+        Ok(_) => 0,
+    }
+}
+
+/// Run the compiled region at the finger if there is one, executing at
+/// most `budget` instructions. Returns how many were executed; zero means
+/// the caller should step the interpreter instead.
+pub(crate) fn run_region(machine: &mut Machine, budget: u64) -> u64 {
+    if machine.halted {
+        return 0;
+    }
+    let start = machine.fin;
+    let region = match machine.jit.regions.get(start).and_then(Clone::clone) {
+        Some(region) => region,
+        None => {
+            let jit = &mut machine.jit;
+            if jit.compiles.get(start) == Some(&MAX_COMPILES) {
+                return 0;
+            }
+            if jit.heat.len() <= start {
+                jit.heat.resize(start + 1, 0);
+            }
+            jit.heat[start] = jit.heat[start].saturating_add(1);
+            if jit.heat[start] < HOT_THRESHOLD && !jit.single {
+                return 0;
+            }
+            match jit.compile(machine.arrays.program(), start) {
+                Some(region) => region,
+                None => return 0,
+            }
+        }
+    };
+    if region.len as u64 > budget {
+        return 0;
+    }
+
+    let executed = unsafe {
+        let machine: *mut Machine = machine;
+        let registers = (*machine).reg.as_mut_ptr();
+        let finger = ptr::addr_of_mut!((*machine).fin);
+        (region.entry)(machine, registers, finger, budget)
+    };
+    machine.jit.retired.clear();
+    machine.cycles += executed;
+    executed
+}
+
+/// Where a compiled and an interpreted machine first disagreed.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Divergence {
+    pub cycles: u64,
+    /// The offset of the region last run, or of the instruction itself when
+    /// compiling one instruction at a time singles it out.
+    pub offset: usize,
+    /// Whether `offset` is that of the instruction.
+    pub instruction: bool,
+    pub jit_finger: usize,
+    pub interpreter_finger: usize,
+    pub jit_registers: [u32; 8],
+    pub interpreter_registers: [u32; 8],
+    pub detail: &'static str,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let place = if self.instruction {
+            "instruction"
+        } else {
+            "region"
+        };
+        write!(
+            f,
+            "{} after {} cycles, by the {} at {:#x}: jit finger {:#x} registers {:08x?}, \
+             interpreter finger {:#x} registers {:08x?}",
+            self.detail,
+            self.cycles,
+            place,
+            self.offset,
+            self.jit_finger,
+            self.jit_registers,
+            self.interpreter_finger,
+            self.interpreter_registers
+        )
+    }
+}
+
+// Comparing every array is expensive, do it every so many regions
+const MEMORY_CHECK_INTERVAL: u64 = 1 << 10;
+
+/// A compiled and an interpreted machine booted from the same scroll.
+struct Pair {
+    compiled: Machine,
+    interpreted: Machine,
+    compiled_io: BufferIo,
+    interpreted_io: BufferIo,
+}
+
+impl Pair {
+    fn boot(scroll: &[u8], input: &[u8]) -> Self {
+        let compiled_io = BufferIo::new(input);
+        let interpreted_io = BufferIo::new(input);
+        let mut compiled = Machine::new(compiled_io.clone(), &mut &scroll[..]);
+        compiled.set_jit(true);
+        let interpreted = Machine::new(interpreted_io.clone(), &mut &scroll[..]);
+        Pair {
+            compiled,
+            interpreted,
+            compiled_io,
+            interpreted_io,
+        }
+    }
+
+    /// Run the compiled machine through the region at its finger, or one
+    /// step if there is none, and the interpreter as many cycles. Returns
+    /// whether the compiled machine stopped.
+    fn advance(&mut self, budget: u64) -> bool {
+        let stopped = run_region(&mut self.compiled, budget) == 0
+            && self.compiled.step() != Ok(StepOutcome::Running);
+        let behind = self.compiled.cycles() - self.interpreted.cycles();
+        self.interpreted.run(RunLimit::cycles(behind));
+        stopped
+    }
+
+    /// The first difference in cycles, finger, registers or halting.
+    fn registers_differ(&self) -> Option<&'static str> {
+        let (compiled, interpreted) = (&self.compiled, &self.interpreted);
+        if compiled.cycles() != interpreted.cycles() {
+            Some("cycle counts differ")
+        } else if compiled.finger() != interpreted.finger() {
+            Some("fingers differ")
+        } else if compiled.reg != interpreted.reg {
+            Some("registers differ")
+        } else if compiled.halted() != interpreted.halted() {
+            Some("only one machine halted")
+        } else {
+            None
+        }
+    }
+
+    /// Whether what `instruction`, executed with `registers`, wrote to the
+    /// arrays or the output differs.
+    fn effects_differ(
+        &self,
+        instruction: Instruction,
+        registers: &[u32; 8],
+    ) -> Option<&'static str> {
+        let (compiled, interpreted) = (&self.compiled, &self.interpreted);
+        let r = registers;
+        let same = match instruction {
+            Amend(Pointers { a, b, .. }) => {
+                compiled.word(r[a], r[b]) == interpreted.word(r[a], r[b])
+            }
+            Allocate(Pointers { b, .. }) => {
+                let id = interpreted.reg[b];
+                compiled.array(id) == interpreted.array(id)
+            }
+            Abandon(Pointers { c, .. }) => {
+                compiled.array(r[c]).is_none() == interpreted.array(r[c]).is_none()
+            }
+            Load(Pointers { b, .. }) if r[b] != 0 => compiled.array(0) == interpreted.array(0),
+            Out(_) => return self.output_differs(),
+            _ => true,
+        };
+        if same {
+            None
+        } else {
+            Some("arrays differ")
+        }
+    }
+
+    fn output_differs(&self) -> Option<&'static str> {
+        if self.compiled_io.output() != self.interpreted_io.output() {
+            Some("output differs")
+        } else {
+            None
+        }
+    }
+
+    fn divergence(&self, detail: &'static str, offset: usize, instruction: bool) -> Divergence {
+        Divergence {
+            cycles: self.compiled.cycles(),
+            offset,
+            instruction,
+            jit_finger: self.compiled.finger(),
+            interpreter_finger: self.interpreted.finger(),
+            jit_registers: self.compiled.reg,
+            interpreter_registers: self.interpreted.reg,
+            detail,
+        }
+    }
+}
+
+/// Boot `scroll` twice, one machine compiled and one interpreted, feed both
+/// `input` and run them region by region for up to `cycles` cycles.
+/// Returns how many cycles both ran identically.
+///
+/// Arrays are only compared every so many regions, so on a divergence the
+/// run is replayed from the last point the machines matched in full, this
+/// time compiling one instruction at a time and comparing the machines
+/// after each, to report the first instruction that differs.
+pub fn lockstep(scroll: &[u8], input: &[u8], cycles: u64) -> Result<u64, Divergence> {
+    compare(&|| Pair::boot(scroll, input), cycles)
+}
+
+fn compare(boot: &dyn Fn() -> Pair, cycles: u64) -> Result<u64, Divergence> {
+    let mut pair = boot();
+    let mut regions = 0;
+    let mut verified = 0;
+    while pair.compiled.cycles() < cycles {
+        let offset = pair.compiled.finger();
+        let stopped = pair.advance(cycles - pair.compiled.cycles());
+        regions += 1;
+
+        let memory = stopped || regions % MEMORY_CHECK_INTERVAL == 0;
+        let detail = pair.registers_differ().or_else(|| pair.output_differs());
+        let detail = match detail {
+            None if memory && pair.compiled.arrays != pair.interpreted.arrays => {
+                Some("arrays differ")
+            }
+            detail => detail,
+        };
+        if let Some(detail) = detail {
+            let found = pair.compiled.cycles();
+            return Err(localise(boot, cycles, verified, found)
+                .unwrap_or_else(|| pair.divergence(detail, offset, false)));
+        }
+        if memory {
+            verified = pair.compiled.cycles();
+        }
+        if stopped {
+            break;
+        }
+    }
+    Ok(pair.compiled.cycles())
+}
+
+/// Replay a `compare` of up to `cycles` cycles to `verified`, where the
+/// machines last matched in full, then carry on to `found` one compiled
+/// instruction at a time. Returns the first instruction after which the
+/// machines differ, if any does.
+fn localise(boot: &dyn Fn() -> Pair, cycles: u64, verified: u64, found: u64) -> Option<Divergence> {
+    let mut pair = boot();
+    // The same budgets as the first run stop the regions at the same places
+    while pair.compiled.cycles() < verified {
+        pair.advance(cycles - pair.compiled.cycles());
+    }
+    pair.compiled.jit.clear();
+    pair.compiled.jit.single = true;
+    while pair.compiled.cycles() < found {
+        let offset = pair.interpreted.finger();
+        let registers = pair.interpreted.reg;
+        let instruction = pair.interpreted.instruction().ok();
+        let stopped = pair.advance(1);
+        let detail = pair.registers_differ().or_else(|| {
+            instruction.and_then(|instruction| pair.effects_differ(instruction, &registers))
+        });
+        if let Some(detail) = detail {
+            return Some(pair.divergence(detail, offset, true));
+        }
+        if stopped {
+            break;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asm::{assemble, boot_words};
+    use crate::{Fault, RunOutcome};
+
+    fn scroll(program: &[u32]) -> Vec<u8> {
+        let mut scroll = Vec::new();
+        crate::write_scroll(&mut scroll, program).unwrap();
+        scroll
+    }
+
+    fn op(opcode: u32, a: u32, b: u32, c: u32) -> u32 {
+        opcode << 28 | a << 6 | b << 3 | c
+    }
+
+    fn ortho(a: u32, value: u32) -> u32 {
+        13 << 28 | a << 25 | value
+    }
+
+    #[test]
+    fn test_matches_interpreter() {
+        let program = [
+            ortho(1, 40),
+            op(6, 4, 0, 0), // r4 <- all ones
+            ortho(6, 5),
+            op(0, 0, 0, 0),
+            op(0, 0, 0, 0),
+            // Loop: r2 += r1 * 3 / 2, r3 <- r2 nand r0, out r6, r1 -= 1
+            ortho(5, 3),
+            op(4, 0, 1, 5),
+            ortho(5, 2),
+            op(5, 0, 0, 5),
+            op(3, 2, 2, 0),
+            op(6, 3, 2, 0),
+            op(10, 0, 0, 6),
+            op(3, 1, 1, 4),
+            // Back to 5 while r1 is non-zero, otherwise on to the halt
+            ortho(5, 16),
+            op(0, 5, 6, 1),
+            op(12, 0, 7, 5),
+            op(7, 0, 0, 0),
+        ];
+        let cycles = lockstep(&scroll(&program), &[], 10_000).unwrap();
+        assert_eq!(cycles, 5 + 40 * 11 + 1);
+
+        let mut machine = boot_words(&program, BufferIo::default());
+        machine.set_jit(true);
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Halted);
+        assert_eq!(machine.reg[2], (1..=40).map(|i| i * 3 / 2).sum::<u32>());
+        assert!(machine.jit_stats().regions_compiled > 0);
+
+        // A native loop still stops on the exact cycle
+        let mut jit = boot_words(&program, BufferIo::default());
+        jit.set_jit(true);
+        let mut plain = boot_words(&program, BufferIo::default());
+        for limit in [300, 7, 1] {
+            assert_eq!(jit.run(RunLimit::cycles(limit)), RunOutcome::CycleLimit);
+            assert_eq!(plain.run(RunLimit::cycles(limit)), RunOutcome::CycleLimit);
+            assert_eq!((jit.finger(), jit.reg), (plain.finger(), plain.reg));
+        }
+    }
+
+    #[test]
+    fn test_fault_left_to_interpreter() {
+        // Count r1 down from 17, dividing by it every iteration
+        let program = [
+            ortho(1, 17),
+            op(6, 4, 0, 0),
+            ortho(6, 3),
+            op(3, 1, 1, 4),
+            op(5, 0, 1, 1),
+            op(12, 0, 7, 6),
+        ];
+        let mut machine = boot_words(&program, BufferIo::default());
+        machine.set_jit(true);
+        assert!(matches!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Fault(Fault::DivisionByZero { finger: 4, .. })
+        ));
+        assert_eq!(machine.reg[1], 0);
+        assert!(machine.jit_stats().regions_compiled > 0);
+        assert!(lockstep(&scroll(&program), &[], 1000).is_ok());
+    }
+
+    #[test]
+    fn test_amend_deoptimises() {
+        // r0 <- 1; amend [r4][r1] <- r2; load r4 r4, with r2 swapped for
+        // `ortho r0 <- 42` once the loop has been compiled
+        let program = [ortho(0, 1), op(2, 4, 1, 2), op(12, 0, 4, 4)];
+        let mut machine = boot_words(&program, BufferIo::default());
+        machine.set_jit(true);
+        machine.reg[2] = ortho(0, 1);
+        machine.run(RunLimit::cycles(3 * u64::from(HOT_THRESHOLD) + 3));
+        assert!(machine.jit_stats().regions_compiled > 0);
+        machine.reg[2] = ortho(0, 42);
+        machine.run(RunLimit::cycles(6));
+        assert_eq!(machine.reg[0], 42);
+        assert!(machine.jit_stats().regions_invalidated > 0);
+    }
+
+    #[test]
+    fn test_recompiles_capped() {
+        // Amends its own first instruction with the same word every pass
+        let program = [ortho(0, 1), op(2, 4, 1, 2), op(12, 0, 4, 4)];
+        let mut machine = boot_words(&program, BufferIo::default());
+        machine.set_jit(true);
+        machine.reg[2] = ortho(0, 1);
+        machine.run(RunLimit::cycles(100_000));
+        let stats = machine.jit_stats();
+        assert_eq!(machine.jit.compiles[0], MAX_COMPILES);
+        assert_eq!(stats.regions_invalidated, u64::from(MAX_COMPILES));
+        assert!(machine.jit.regions[0].is_none());
+        assert_eq!(machine.cycles(), 100_000);
+    }
+
+    #[test]
+    fn test_lockstep_finds_instruction() {
+        // Sums the words at offsets 50 down to 1, in a loop compiled long
+        // before it reaches the word the interpreter sees changed
+        let mut program = assemble(
+            "
+                    ortho r1, 51
+                    nand r2, r0, r0
+                    ortho r6, loop
+            loop:   add r1, r1, r2
+                    index r3, r4, r1
+                    add r5, r5, r3
+                    ortho r7, done
+                    cmov r7, r6, r1
+                    load r4, r7
+            done:   halt
+            ",
+        )
+        .unwrap();
+        program.resize(64, 0);
+        let scroll = scroll(&program);
+        let boot = || {
+            let mut pair = Pair::boot(&scroll, &[]);
+            pair.interpreted.set_word(0, 30, 7).unwrap();
+            pair
+        };
+        let divergence = compare(&boot, 1000).unwrap_err();
+        assert_eq!(divergence.detail, "registers differ");
+        assert!(divergence.instruction);
+        assert_eq!(divergence.offset, 4);
+        assert_eq!(divergence.cycles, 3 + 6 * 20 + 2);
+        assert_eq!(divergence.jit_registers[3], 0);
+        assert_eq!(divergence.interpreter_registers[3], 7);
+        assert_eq!(divergence.jit_registers[1], 30);
+    }
+
+    #[test]
+    fn sandmark() {
+        let sand_mark = include_bytes!("../static/media/sandmark.umz");
+        assert!(lockstep(sand_mark, &[], 2_000_000).is_ok());
+    }
+}
@@ -1,15 +1,39 @@
-use std::sync::mpsc::{Receiver, Sender};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
 
+pub mod arrays;
+pub mod asm;
+pub mod breakpoint;
+mod codec;
+pub mod coverage;
+pub mod disasm;
+pub mod gdb;
+pub mod history;
+pub mod io;
+#[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+pub mod jit;
+pub mod profile;
+pub mod record;
+pub mod snapshot;
+#[cfg(feature = "threaded")]
+pub mod threaded;
+pub mod trace;
 #[cfg(feature = "web")]
 pub mod webmachine;
 
-type Stacks = Vec<Vec<u32>>;
+use crate::arrays::{ArrayStats, Arrays};
+use crate::breakpoint::Breakpoint;
+use crate::history::History;
+use crate::io::{Input, UmIo};
+use crate::record::{InputEvent, Recording};
+use crate::trace::{TraceEvent, Tracer};
 
-#[derive(Debug, PartialEq)]
-struct Pointers {
-    a: usize,
-    b: usize,
-    c: usize,
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Pointers {
+    pub a: usize,
+    pub b: usize,
+    pub c: usize,
 }
 
 impl Pointers {
@@ -22,10 +46,10 @@ impl Pointers {
     }
 }
 
-#[derive(Debug, PartialEq)]
-struct OrthoPointers {
-    a: usize,
-    value: u32,
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct OrthoPointers {
+    pub a: usize,
+    pub value: u32,
 }
 
 impl OrthoPointers {
@@ -37,8 +61,8 @@ impl OrthoPointers {
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum Instruction {
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Instruction {
     Move(Pointers),
     Index(Pointers),
     Amend(Pointers),
@@ -57,63 +81,721 @@ enum Instruction {
 
 use self::Instruction::*;
 
+/// A UM fail condition. The machine is left pointing at the offending
+/// instruction so it can be inspected after the fact.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Fault {
+    InvalidOpcode {
+        finger: usize,
+        word: u32,
+    },
+    InactiveArray {
+        finger: usize,
+        instruction: Instruction,
+        array: u32,
+    },
+    IndexOutOfBounds {
+        finger: usize,
+        instruction: Instruction,
+        array: u32,
+        offset: u32,
+    },
+    AmendOutOfBounds {
+        finger: usize,
+        instruction: Instruction,
+        array: u32,
+        offset: u32,
+    },
+    DivisionByZero {
+        finger: usize,
+        instruction: Instruction,
+    },
+    AbandonProgram {
+        finger: usize,
+        instruction: Instruction,
+    },
+    AbandonInactive {
+        finger: usize,
+        instruction: Instruction,
+        array: u32,
+    },
+    FingerOutOfRange {
+        finger: usize,
+    },
+    OutputOutOfRange {
+        finger: usize,
+        instruction: Instruction,
+        value: u32,
+    },
+    EndOfInput {
+        finger: usize,
+        instruction: Instruction,
+    },
+    OutputFailed {
+        finger: usize,
+        instruction: Instruction,
+    },
+    InputFailed {
+        finger: usize,
+        instruction: Instruction,
+    },
+    /// Going on would take the machine past one of its `Limits`. Running
+    /// out of cycles happens before the next instruction is decoded.
+    LimitExceeded {
+        finger: usize,
+        instruction: Option<Instruction>,
+        resource: Resource,
+        limit: u64,
+    },
+}
+
+impl Fault {
+    pub fn finger(&self) -> usize {
+        use self::Fault::*;
+        match *self {
+            InvalidOpcode { finger, .. }
+            | InactiveArray { finger, .. }
+            | IndexOutOfBounds { finger, .. }
+            | AmendOutOfBounds { finger, .. }
+            | DivisionByZero { finger, .. }
+            | AbandonProgram { finger, .. }
+            | AbandonInactive { finger, .. }
+            | FingerOutOfRange { finger }
+            | OutputOutOfRange { finger, .. }
+            | EndOfInput { finger, .. }
+            | OutputFailed { finger, .. }
+            | InputFailed { finger, .. }
+            | LimitExceeded { finger, .. } => finger,
+        }
+    }
+
+    /// The decoded instruction that failed, if the word at the finger
+    /// could be decoded at all.
+    pub fn instruction(&self) -> Option<Instruction> {
+        use self::Fault::*;
+        match *self {
+            InvalidOpcode { .. } | FingerOutOfRange { .. } => None,
+            InactiveArray { instruction, .. }
+            | IndexOutOfBounds { instruction, .. }
+            | AmendOutOfBounds { instruction, .. }
+            | DivisionByZero { instruction, .. }
+            | AbandonProgram { instruction, .. }
+            | AbandonInactive { instruction, .. }
+            | OutputOutOfRange { instruction, .. }
+            | EndOfInput { instruction, .. }
+            | OutputFailed { instruction, .. }
+            | InputFailed { instruction, .. } => Some(instruction),
+            LimitExceeded { instruction, .. } => instruction,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Fault::*;
+        match self {
+            InvalidOpcode { word, .. } => write!(f, "invalid opcode in word {:#010x}", word),
+            InactiveArray { array, .. } => write!(f, "array {} is not active", array),
+            IndexOutOfBounds { array, offset, .. } => {
+                write!(
+                    f,
+                    "index of array {} out of bounds at offset {}",
+                    array, offset
+                )
+            }
+            AmendOutOfBounds { array, offset, .. } => {
+                write!(
+                    f,
+                    "amend of array {} out of bounds at offset {}",
+                    array, offset
+                )
+            }
+            DivisionByZero { .. } => write!(f, "division by zero"),
+            AbandonProgram { .. } => write!(f, "abandoned array 0"),
+            AbandonInactive { array, .. } => write!(f, "abandoned inactive array {}", array),
+            FingerOutOfRange { .. } => write!(f, "finger outside of array 0"),
+            OutputOutOfRange { value, .. } => write!(f, "output value {} exceeds 255", value),
+            EndOfInput { .. } => write!(f, "input exhausted"),
+            OutputFailed { .. } => write!(f, "output could not be written"),
+            InputFailed { .. } => write!(f, "input could not be read"),
+            LimitExceeded {
+                resource, limit, ..
+            } => write!(f, "{} limit of {} exceeded", resource, limit),
+        }?;
+        write!(f, " (finger {:#x})", self.finger())
+    }
+}
+
+impl std::error::Error for Fault {}
+
+/// Caps on what a machine may consume, for running programs that cannot be
+/// trusted. `None` leaves a resource unbounded, as it is by default.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Limits {
+    /// Arrays live at once, array 0 included.
+    pub live_arrays: Option<u64>,
+    /// Words across every live array.
+    pub total_words: Option<u64>,
+    /// Words in any one allocation.
+    pub array_words: Option<u64>,
+    /// Bytes written by `Out` over the machine's life.
+    pub output_bytes: Option<u64>,
+    /// Instructions executed over the machine's life.
+    pub cycles: Option<u64>,
+}
+
+/// Which of the `Limits` was exceeded.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Resource {
+    LiveArrays,
+    TotalWords,
+    ArrayWords,
+    OutputBytes,
+    Cycles,
+}
+
+impl Limits {
+    fn get(&self, resource: Resource) -> Option<u64> {
+        match resource {
+            Resource::LiveArrays => self.live_arrays,
+            Resource::TotalWords => self.total_words,
+            Resource::ArrayWords => self.array_words,
+            Resource::OutputBytes => self.output_bytes,
+            Resource::Cycles => self.cycles,
+        }
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Resource::LiveArrays => "live array",
+            Resource::TotalWords => "total word",
+            Resource::ArrayWords => "array size",
+            Resource::OutputBytes => "output byte",
+            Resource::Cycles => "cycle",
+        })
+    }
+}
+
+/// Why `Machine::set_word` or `Machine::set_register` could not write.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AccessError {
+    InactiveArray(u32),
+    OutOfBounds { array: u32, offset: u32 },
+    NoRegister(usize),
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccessError::InactiveArray(array) => write!(f, "array {} is not active", array),
+            AccessError::OutOfBounds { array, offset } => {
+                write!(f, "offset {} is outside array {}", offset, array)
+            }
+            AccessError::NoRegister(register) => write!(f, "there is no register r{}", register),
+        }
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+/// What a single successful cycle left the machine doing.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StepOutcome {
+    Running,
+    Blocked,
+    Halted,
+}
+
+pub type StepResult = Result<StepOutcome, Fault>;
+
+/// Bounds on a call to `Machine::run`. The default runs until the machine
+/// halts, faults or starves for input.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RunLimit {
+    pub cycles: Option<u64>,
+    pub deadline: Option<Instant>,
+}
+
+impl RunLimit {
+    pub fn cycles(cycles: u64) -> Self {
+        RunLimit {
+            cycles: Some(cycles),
+            deadline: None,
+        }
+    }
+
+    pub fn until(deadline: Instant) -> Self {
+        RunLimit {
+            cycles: None,
+            deadline: Some(deadline),
+        }
+    }
+}
+
+/// Why `Machine::run` returned.
+#[derive(Debug, PartialEq, Clone)]
+pub enum RunOutcome {
+    Halted,
+    Blocked,
+    Fault(Fault),
+    CycleLimit,
+    Deadline,
+    /// Stopped before the instruction at the finger, which fired the
+    /// breakpoint with this identifier.
+    Breakpoint(usize),
+}
+
+/// Run a single cycle, reporting fail conditions instead of panicking.
+/// On a fault the finger is left on the offending instruction.
+#[deprecated(note = "use `Machine::step`")]
+pub fn try_spin(machine: &mut Machine) -> StepResult {
+    machine.step()
+}
+
+/// Run a single cycle, returning `None` once the machine halts.
+///
+/// Panics on any fail condition; use `Machine::step` to handle them.
+#[deprecated(note = "use `Machine::run` or `Machine::step`")]
+pub fn spin(mut machine: Machine) -> Option<Machine> {
+    match machine.run(RunLimit::cycles(1)) {
+        RunOutcome::Halted => None,
+        RunOutcome::Fault(fault) => panic!("{}", fault),
+        _ => Some(machine),
+    }
+}
+
+// Reading the clock every cycle would dominate the run loop
+const DEADLINE_CHECK_INTERVAL: u64 = 1 << 12;
+
+/// How `In` behaves once the input side has been closed.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum EofPolicy {
+    /// Load 0xFFFFFFFF into the register, as the spec requires.
+    #[default]
+    AllOnes,
+    /// Leave the machine blocked on the `In` instruction.
+    Block,
+    /// Stop with `Fault::EndOfInput`.
+    Fault,
+}
+
 pub struct Machine {
     fin: usize,
     reg: [u32; 8],
-    stacks: Stacks,
-    available: Vec<usize>,
+    arrays: Arrays,
+    cycles: u64,
+    halted: bool,
+    eof: EofPolicy,
+    limits: Limits,
+    output_bytes: u64,
+    recording: Option<Recording>,
+    tracer: Option<Box<dyn Tracer>>,
+    history: Option<Box<History>>,
+    breakpoints: Vec<(usize, Breakpoint)>,
+    next_breakpoint: usize,
+    // The finger `run` last stopped on for a breakpoint, until the machine
+    // moves on, so running again carries on past it
+    stopped_on: Option<usize>,
+
+    code: Vec<Option<Instruction>>,
+    decode_cache: bool,
+    #[cfg(feature = "threaded")]
+    blocks: threaded::Blocks,
+    #[cfg(feature = "threaded")]
+    threaded: bool,
+    #[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+    jit: jit::Jit,
 
-    inbox: Receiver<u32>,
-    outbox: Sender<u32>,
+    io: Box<dyn UmIo>,
 }
 
-impl Machine {
+// Harnesses hand machines to worker threads
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Machine>();
+};
 
+impl Machine {
     pub fn finger(&self) -> usize {
         self.fin
     }
 
-    fn advance(&mut self) -> Instruction {
-        let instruction = self.instruction();
-        self.fin += 1;
-        instruction
+    /// Instructions executed so far, including the final `Halt`.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn registers(&self) -> [u32; 8] {
+        self.reg
+    }
+
+    pub fn set_register(&mut self, register: usize, value: u32) -> Result<(), AccessError> {
+        *self
+            .reg
+            .get_mut(register)
+            .ok_or(AccessError::NoRegister(register))? = value;
+        self.restart_history();
+        Ok(())
+    }
+
+    /// Move the finger, e.g. to skip an instruction while debugging. A
+    /// halted machine carries on from the new finger.
+    pub fn set_finger(&mut self, finger: usize) {
+        self.fin = finger;
+        self.halted = false;
+        self.stopped_on = None;
+        self.restart_history();
+    }
+
+    /// The words of a live array.
+    pub fn array(&self, id: u32) -> Option<&[u32]> {
+        self.arrays.get(id).map(|array| array.as_slice())
+    }
+
+    pub fn word(&self, id: u32, offset: u32) -> Option<u32> {
+        self.array(id)
+            .and_then(|array| array.get(offset as usize))
+            .cloned()
+    }
+
+    /// Write a word of a live array as `Amend` would, without counting a
+    /// cycle.
+    pub fn set_word(&mut self, id: u32, offset: u32, value: u32) -> Result<(), AccessError> {
+        let array = self
+            .arrays
+            .get_mut(id)
+            .ok_or(AccessError::InactiveArray(id))?;
+        if offset as usize >= array.len() {
+            return Err(AccessError::OutOfBounds { array: id, offset });
+        }
+        Arc::make_mut(array)[offset as usize] = value;
+        if id == 0 {
+            self.invalidate_code(offset as usize);
+        }
+        self.restart_history();
+        Ok(())
+    }
+
+    /// Identifiers and lengths of the live arrays, in identifier order.
+    pub fn live_arrays(&self) -> impl Iterator<Item = (u32, usize)> + '_ {
+        self.arrays
+            .slots()
+            .iter()
+            .enumerate()
+            .filter_map(|(id, slot)| slot.as_ref().map(|array| (id as u32, array.len())))
+    }
+
+    /// Run a single cycle. On a fault the finger is left on the offending
+    /// instruction; once halted every further step reports `Halted`.
+    pub fn step(&mut self) -> StepResult {
+        if self.halted {
+            return Ok(StepOutcome::Halted);
+        }
+        self.check_limit(Resource::Cycles, self.cycles + 1, None)?;
+        let finger = self.fin;
+        let before = self.reg;
+        let instruction = self.advance()?;
+        let undo = self
+            .history
+            .as_ref()
+            .map(|_| self.prepare_undo(finger, instruction));
+        let outcome = self.execute(instruction);
+        match outcome {
+            Ok(StepOutcome::Running) => self.cycles += 1,
+            Ok(StepOutcome::Halted) => {
+                self.cycles += 1;
+                self.halted = true;
+            }
+            Ok(StepOutcome::Blocked) => return outcome,
+            Err(_) => {
+                self.fin = finger;
+                return outcome;
+            }
+        }
+        self.stopped_on = None;
+        if let Some(undo) = undo {
+            self.push_undo(undo, instruction);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&TraceEvent {
+                cycle: self.cycles - 1,
+                finger,
+                instruction,
+                before,
+                after: self.reg,
+            });
+            if let Instruction::Load(Pointers { b, .. }) = instruction {
+                if before[b] != 0 {
+                    tracer.program(before[b], self.arrays.program());
+                }
+            }
+        }
+        outcome
+    }
+
+    /// Have `tracer` see every instruction from now on, or stop tracing
+    /// with `None`. Returns the tracer it replaces.
+    pub fn set_tracer(&mut self, mut tracer: Option<Box<dyn Tracer>>) -> Option<Box<dyn Tracer>> {
+        if let Some(tracer) = &mut tracer {
+            tracer.program(0, self.arrays.program());
+        }
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    /// Step until the machine halts, faults, starves for input, reaches a
+    /// breakpoint or exhausts `limit`.
+    pub fn run(&mut self, limit: RunLimit) -> RunOutcome {
+        let start = self.cycles;
+        let mut next_deadline_check = 0;
+        // Code run without breakpoints does not keep `stopped_on` current
+        if self.breakpoints.is_empty() {
+            self.stopped_on = None;
+        }
+        loop {
+            let elapsed = self.cycles - start;
+            if limit.cycles == Some(elapsed) {
+                return RunOutcome::CycleLimit;
+            }
+            if let Some(deadline) = limit.deadline {
+                if elapsed >= next_deadline_check {
+                    if Instant::now() >= deadline {
+                        return RunOutcome::Deadline;
+                    }
+                    next_deadline_check = elapsed + DEADLINE_CHECK_INTERVAL;
+                }
+            }
+            if !self.breakpoints.is_empty() && self.stopped_on != Some(self.fin) {
+                if let Some(id) = self.breakpoint_hit() {
+                    self.stopped_on = Some(self.fin);
+                    return RunOutcome::Breakpoint(id);
+                }
+            }
+            #[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+            {
+                if self.jit.enabled && !self.observed() {
+                    let mut budget = self.budget(limit, elapsed);
+                    // Compiled loops only come back when the budget runs out
+                    if limit.deadline.is_some() {
+                        budget = budget.min(next_deadline_check - elapsed);
+                    }
+                    if jit::run_region(self, budget) > 0 {
+                        continue;
+                    }
+                }
+            }
+            #[cfg(feature = "threaded")]
+            {
+                if self.threaded && !self.observed() {
+                    let budget = self.budget(limit, elapsed);
+                    match threaded::run_block(self, budget) {
+                        Ok(0) => {}
+                        Ok(_) => continue,
+                        Err(fault) => return RunOutcome::Fault(fault),
+                    }
+                }
+            }
+            match self.step() {
+                Ok(StepOutcome::Running) => {}
+                Ok(StepOutcome::Blocked) => return RunOutcome::Blocked,
+                Ok(StepOutcome::Halted) => return RunOutcome::Halted,
+                Err(fault) => return RunOutcome::Fault(fault),
+            }
+        }
+    }
+
+    /// Whether something needs to see every cycle, so translated code
+    /// cannot be used.
+    #[cfg(any(
+        feature = "threaded",
+        all(feature = "jit", target_os = "linux", target_arch = "x86_64")
+    ))]
+    fn observed(&self) -> bool {
+        self.tracer.is_some() || self.history.is_some() || !self.breakpoints.is_empty()
+    }
+
+    /// How many cycles a translated block may run without overshooting
+    /// either `limit` or the machine's own cycle limit.
+    #[cfg(any(
+        feature = "threaded",
+        all(feature = "jit", target_os = "linux", target_arch = "x86_64")
+    ))]
+    fn budget(&self, limit: RunLimit, elapsed: u64) -> u64 {
+        let budget = limit.cycles.map_or(u64::MAX, |cycles| cycles - elapsed);
+        match self.limits.cycles {
+            Some(max) => budget.min(max.saturating_sub(self.cycles)),
+            None => budget,
+        }
+    }
+
+    /// Cap what the machine may consume from here on; usage so far counts
+    /// against the new limits.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    fn check_limit(
+        &self,
+        resource: Resource,
+        wanted: u64,
+        instruction: Option<Instruction>,
+    ) -> Result<(), Fault> {
+        match self.limits.get(resource) {
+            Some(limit) if wanted > limit => Err(Fault::LimitExceeded {
+                finger: self.fin - instruction.map_or(0, |_| 1),
+                instruction,
+                resource,
+                limit,
+            }),
+            _ => Ok(()),
+        }
     }
 
-    fn instruction(&self) -> Instruction {
-        self.stacks
-            .get(0)
-            .map(|scroll| scroll[self.fin])
-            .expect("Finger or stack invalid")
-            .into()
+    /// Run array 0 as translated blocks of threaded code rather than one
+    /// instruction at a time. Off by default.
+    #[cfg(feature = "threaded")]
+    pub fn set_threaded(&mut self, enabled: bool) {
+        self.threaded = enabled;
+        self.blocks.clear();
     }
 
-    fn insert_stack(&mut self, stack_length: usize) -> u32 {
-        if let Some(key) = self.available.pop() {
-            self.stacks[key].clear();
-            self.stacks[key].resize(stack_length, 0);
-            key as u32
+    #[cfg(feature = "threaded")]
+    pub fn threaded_stats(&self) -> threaded::ThreadedStats {
+        self.blocks.stats
+    }
+
+    /// Compile hot regions of array 0 to native code. Off by default; takes
+    /// precedence over the threaded engine.
+    #[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+    pub fn set_jit(&mut self, enabled: bool) {
+        self.jit.clear();
+        self.jit.enabled = enabled;
+    }
+
+    #[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+    pub fn jit_stats(&self) -> jit::JitStats {
+        self.jit.stats
+    }
+
+    pub fn array_stats(&self) -> ArrayStats {
+        self.arrays.stats
+    }
+
+    /// Keep decoded instructions for array 0 between cycles. On by default;
+    /// turning it off decodes every platter as it is executed.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = enabled;
+        self.code.clear();
+    }
+
+    fn advance(&mut self) -> Result<Instruction, Fault> {
+        let instruction = if self.decode_cache {
+            self.cached_instruction()?
         } else {
-            let key = self.stacks.len();
-            self.stacks.push(vec![0; stack_length]);
-            key as u32
+            self.instruction()?
+        };
+        self.fin += 1;
+        Ok(instruction)
+    }
+
+    /// Forget everything decoded or compiled from array 0.
+    fn clear_code(&mut self) {
+        self.code.clear();
+        #[cfg(feature = "threaded")]
+        self.blocks.clear();
+        #[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+        self.jit.clear();
+    }
+
+    /// Forget anything decoded or compiled from this offset of array 0.
+    fn invalidate_code(&mut self, offset: usize) {
+        if let Some(code) = self.code.get_mut(offset) {
+            *code = None;
         }
+        #[cfg(feature = "threaded")]
+        self.blocks.invalidate(offset);
+        #[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+        self.jit.invalidate(offset);
     }
 
-    pub fn new(r: Receiver<u32>, s: Sender<u32>, scroll: &mut std::io::Read) -> Self {
+    /// Decode the instruction at the finger, failing as executing it would
+    /// if the finger is outside array 0 or the word is not an instruction.
+    pub fn instruction(&self) -> Result<Instruction, Fault> {
+        let finger = self.fin;
+        let word = *self
+            .arrays
+            .program()
+            .get(finger)
+            .ok_or(Fault::FingerOutOfRange { finger })?;
+        Instruction::decode(word).ok_or(Fault::InvalidOpcode { finger, word })
+    }
+
+    fn cached_instruction(&mut self) -> Result<Instruction, Fault> {
+        if let Some(&Some(instruction)) = self.code.get(self.fin) {
+            return Ok(instruction);
+        }
+        let instruction = self.instruction()?;
+        // The cache is emptied whenever array 0 is replaced and only grows
+        // as far as the finger reaches, so a `Load` stays cheap
+        if self.code.len() <= self.fin {
+            self.code.resize(self.fin + 1, None);
+        }
+        self.code[self.fin] = Some(instruction);
+        Ok(instruction)
+    }
+
+    pub fn new(io: impl UmIo + 'static, scroll: &mut dyn std::io::Read) -> Self {
+        Self::with_eof_policy(io, scroll, EofPolicy::default())
+    }
+
+    /// Boot a machine that treats the end of its input according to `eof`.
+    pub fn with_eof_policy(
+        io: impl UmIo + 'static,
+        scroll: &mut dyn std::io::Read,
+        eof: EofPolicy,
+    ) -> Self {
         Self {
             fin: 0,
             reg: [0; 8],
-            stacks: vec![read_scroll(scroll)],
-            available: Vec::new(),
-            inbox: r,
-            outbox: s,
+            arrays: Arrays::new(read_scroll(scroll)),
+            cycles: 0,
+            halted: false,
+            eof,
+            limits: Limits::default(),
+            output_bytes: 0,
+            recording: None,
+            tracer: None,
+            history: None,
+            breakpoints: Vec::new(),
+            next_breakpoint: 0,
+            stopped_on: None,
+            code: Vec::new(),
+            decode_cache: true,
+            #[cfg(feature = "threaded")]
+            blocks: threaded::Blocks::default(),
+            #[cfg(feature = "threaded")]
+            threaded: false,
+            #[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+            jit: jit::Jit::default(),
+            io: Box::new(io),
         }
     }
 }
 
-impl From<u32> for Instruction {
-    fn from(i: u32) -> Self {
-        match i >> 28 {
+impl Instruction {
+    /// Decode a platter, or `None` if its opcode is not one of the 14
+    /// defined by the spec.
+    pub fn decode(i: u32) -> Option<Self> {
+        Some(match i >> 28 {
             0 => Instruction::Move(Pointers::new(i)),
             1 => Instruction::Index(Pointers::new(i)),
             2 => Instruction::Amend(Pointers::new(i)),
@@ -128,7 +810,84 @@ impl From<u32> for Instruction {
             11 => Instruction::In(Pointers::new(i)),
             12 => Instruction::Load(Pointers::new(i)),
             13 => Instruction::Ortho(OrthoPointers::new(i)),
-            _ => panic!("Bad Instruction"),
+            _ => return None,
+        })
+    }
+
+    pub fn opcode(&self) -> u32 {
+        match self {
+            Move(_) => 0,
+            Index(_) => 1,
+            Amend(_) => 2,
+            Add(_) => 3,
+            Mul(_) => 4,
+            Div(_) => 5,
+            Nand(_) => 6,
+            Halt(_) => 7,
+            Allocate(_) => 8,
+            Abandon(_) => 9,
+            Out(_) => 10,
+            In(_) => 11,
+            Load(_) => 12,
+            Ortho(_) => 13,
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        MNEMONICS[self.opcode() as usize]
+    }
+
+    /// The platter this instruction decodes from, with the bits no field
+    /// covers cleared.
+    pub fn encode(&self) -> u32 {
+        match *self {
+            Move(Pointers { a, b, c })
+            | Index(Pointers { a, b, c })
+            | Amend(Pointers { a, b, c })
+            | Add(Pointers { a, b, c })
+            | Mul(Pointers { a, b, c })
+            | Div(Pointers { a, b, c })
+            | Nand(Pointers { a, b, c })
+            | Halt(Pointers { a, b, c })
+            | Allocate(Pointers { a, b, c })
+            | Abandon(Pointers { a, b, c })
+            | Out(Pointers { a, b, c })
+            | In(Pointers { a, b, c })
+            | Load(Pointers { a, b, c }) => {
+                self.opcode() << 28 | (a as u32) << 6 | (b as u32) << 3 | c as u32
+            }
+            Ortho(OrthoPointers { a, value }) => 13 << 28 | (a as u32) << 25 | value,
+        }
+    }
+}
+
+/// Assembly names of the opcodes, indexed by opcode.
+pub const MNEMONICS: [&str; 14] = [
+    "cmov", "index", "amend", "add", "mul", "div", "nand", "halt", "alloc", "abandon", "out", "in",
+    "load", "ortho",
+];
+
+/// Assembly syntax, e.g. `add r1, r2, r3` or `ortho r0, 65`. Only the
+/// registers an instruction uses are shown.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        match *self {
+            Move(Pointers { a, b, c })
+            | Index(Pointers { a, b, c })
+            | Amend(Pointers { a, b, c })
+            | Add(Pointers { a, b, c })
+            | Mul(Pointers { a, b, c })
+            | Div(Pointers { a, b, c })
+            | Nand(Pointers { a, b, c }) => write!(f, " r{}, r{}, r{}", a, b, c),
+            Halt(_) => Ok(()),
+            Allocate(Pointers { b, c, .. }) | Load(Pointers { b, c, .. }) => {
+                write!(f, " r{}, r{}", b, c)
+            }
+            Abandon(Pointers { c, .. }) | Out(Pointers { c, .. }) | In(Pointers { c, .. }) => {
+                write!(f, " r{}", c)
+            }
+            Ortho(OrthoPointers { a, value }) => write!(f, " r{}, {}", a, value),
         }
     }
 }
@@ -140,125 +899,503 @@ fn as_u32(word: [u8; 4]) -> u32 {
         .sum()
 }
 
-fn read_scroll(r: &mut std::io::Read) -> Vec<u32> {
+/// Read a scroll as big-endian platters, dropping any trailing partial one.
+pub fn read_scroll(r: &mut dyn std::io::Read) -> Vec<u32> {
     let mut scroll: Vec<u32> = Vec::new();
     let mut word: [u8; 4] = [0; 4];
-    while let Ok(_) = r.read_exact(&mut word) {
+    while r.read_exact(&mut word).is_ok() {
         scroll.push(as_u32(word));
     }
     scroll
 }
 
-static NULL_STACK_ERR: &'static str = "Attempted operation on unallocated stack";
-
-#[cfg(feature = "yew")]
-fn read_byte(machine: &mut Machine) -> Option<u32> {
-    machine.inbox.try_recv().ok()
+/// Write platters as a scroll that `read_scroll` reads back.
+pub fn write_scroll(w: &mut dyn std::io::Write, program: &[u32]) -> std::io::Result<()> {
+    let bytes: Vec<u8> = program.iter().flat_map(|word| word.to_be_bytes()).collect();
+    w.write_all(&bytes)
 }
 
-#[cfg(not(feature = "yew"))]
-fn read_byte(machine: &mut Machine) -> Option<u32> {
-    Some(machine.inbox.recv().unwrap())
-}
+impl Machine {
+    fn record_input(&mut self, byte: Option<u8>) {
+        if let Some(recording) = &mut self.recording {
+            recording.input.push(InputEvent {
+                cycle: self.cycles,
+                byte,
+            });
+        }
+    }
 
-pub fn spin(mut machine: Machine) -> Option<Machine> {
-    match machine.advance() {
-        Move(Pointers { a, b, c }) => {
-            machine.reg[a] = if machine.reg[c] > 0 {
-                machine.reg[b]
-            } else {
-                machine.reg[a]
+    fn execute(&mut self, instruction: Instruction) -> Result<StepOutcome, Fault> {
+        let finger = self.fin - 1;
+        match instruction {
+            Move(Pointers { a, b, c }) => {
+                if self.reg[c] > 0 {
+                    self.reg[a] = self.reg[b]
+                }
             }
-        }
-        Index(Pointers { a, b, c }) => {
-            machine.reg[a] = machine
-                .stacks
-                .get(machine.reg[b] as usize)
-                .expect(NULL_STACK_ERR)[machine.reg[c] as usize]
-        }
-        Amend(Pointers { a, b, c }) => {
-            machine
-                .stacks
-                .get_mut(machine.reg[a] as usize)
-                .expect(NULL_STACK_ERR)[machine.reg[b] as usize] = machine.reg[c]
-        }
-        Add(Pointers { a, b, c }) => machine.reg[a] = machine.reg[b].wrapping_add(machine.reg[c]),
-        Mul(Pointers { a, b, c }) => machine.reg[a] = machine.reg[b].wrapping_mul(machine.reg[c]),
-        Div(Pointers { a, b, c }) => machine.reg[a] = machine.reg[b].wrapping_div(machine.reg[c]),
-        Nand(Pointers { a, b, c }) => machine.reg[a] = !(machine.reg[b] & machine.reg[c]),
-        Halt(_) => {
-            return None;
-        }
-        Allocate(Pointers { b, c, .. }) => {
-            machine.reg[b] = machine.insert_stack(machine.reg[c] as usize);
-        }
-        Abandon(Pointers { c, .. }) => {
-            machine.available.push(machine.reg[c] as usize);
-        }
-        Out(Pointers { c, .. }) => {
-            machine.outbox.send(machine.reg[c]).expect("Output channel closed")
-        }
-        In(Pointers { c, .. }) => {
-            if let Some(b) = read_byte(&mut machine) {
-                machine.reg[c] = b;
-            } else {
-                machine.fin -= 1;
+            Index(Pointers { a, b, c }) => {
+                let (array, offset) = (self.reg[b], self.reg[c]);
+                let stack = self.arrays.get(array).ok_or(Fault::InactiveArray {
+                    finger,
+                    instruction,
+                    array,
+                })?;
+                self.reg[a] = *stack.get(offset as usize).ok_or(Fault::IndexOutOfBounds {
+                    finger,
+                    instruction,
+                    array,
+                    offset,
+                })?
             }
-        },
-        Load(Pointers { b, c, .. }) => {
-            if machine.reg[b] > 0 {
-                machine.stacks[0] = machine
-                    .stacks
-                    .get(machine.reg[b] as usize)
-                    .expect(NULL_STACK_ERR)
-                    .clone();
+            Amend(Pointers { a, b, c }) => {
+                let (array, offset, value) = (self.reg[a], self.reg[b], self.reg[c]);
+                let stack = self.arrays.get_mut(array).ok_or(Fault::InactiveArray {
+                    finger,
+                    instruction,
+                    array,
+                })?;
+                if offset as usize >= stack.len() {
+                    return Err(Fault::AmendOutOfBounds {
+                        finger,
+                        instruction,
+                        array,
+                        offset,
+                    });
+                }
+                Arc::make_mut(stack)[offset as usize] = value;
+                if array == 0 {
+                    self.invalidate_code(offset as usize);
+                }
             }
-            machine.fin = machine.reg[c] as usize;
-        }
-        Ortho(OrthoPointers { a, value }) => machine.reg[a] = value,
-    };
-    Some(machine)
+            Add(Pointers { a, b, c }) => self.reg[a] = self.reg[b].wrapping_add(self.reg[c]),
+            Mul(Pointers { a, b, c }) => self.reg[a] = self.reg[b].wrapping_mul(self.reg[c]),
+            Div(Pointers { a, b, c }) => {
+                self.reg[a] = self.reg[b]
+                    .checked_div(self.reg[c])
+                    .ok_or(Fault::DivisionByZero {
+                        finger,
+                        instruction,
+                    })?
+            }
+            Nand(Pointers { a, b, c }) => self.reg[a] = !(self.reg[b] & self.reg[c]),
+            Halt(_) => {
+                self.fin = finger;
+                return Ok(StepOutcome::Halted);
+            }
+            Allocate(Pointers { b, c, .. }) => {
+                let words = u64::from(self.reg[c]);
+                let stats = self.arrays.stats;
+                let instruction = Some(instruction);
+                self.check_limit(Resource::ArrayWords, words, instruction)?;
+                self.check_limit(Resource::LiveArrays, stats.live_arrays + 1, instruction)?;
+                self.check_limit(Resource::TotalWords, stats.live_words + words, instruction)?;
+                self.reg[b] = self.arrays.allocate(words as usize);
+            }
+            Abandon(Pointers { c, .. }) => {
+                let array = self.reg[c];
+                if array == 0 {
+                    return Err(Fault::AbandonProgram {
+                        finger,
+                        instruction,
+                    });
+                }
+                if !self.arrays.abandon(array) {
+                    return Err(Fault::AbandonInactive {
+                        finger,
+                        instruction,
+                        array,
+                    });
+                }
+            }
+            Out(Pointers { c, .. }) => {
+                let value = self.reg[c];
+                if value > 255 {
+                    return Err(Fault::OutputOutOfRange {
+                        finger,
+                        instruction,
+                        value,
+                    });
+                }
+                self.check_limit(
+                    Resource::OutputBytes,
+                    self.output_bytes + 1,
+                    Some(instruction),
+                )?;
+                self.io
+                    .write_byte(value as u8)
+                    .map_err(|_| Fault::OutputFailed {
+                        finger,
+                        instruction,
+                    })?;
+                self.output_bytes += 1;
+                if let Some(recording) = &mut self.recording {
+                    recording.output.push(value as u8);
+                }
+            }
+            In(Pointers { c, .. }) => match (self.io.read_byte(), self.eof) {
+                (Input::Byte(b), _) => {
+                    self.reg[c] = u32::from(b);
+                    self.record_input(Some(b));
+                }
+                (Input::Eof, EofPolicy::AllOnes) => {
+                    self.reg[c] = 0xFFFF_FFFF;
+                    self.record_input(None);
+                }
+                (Input::Eof, EofPolicy::Fault) => {
+                    return Err(Fault::EndOfInput {
+                        finger,
+                        instruction,
+                    })
+                }
+                (Input::Error(_), _) => {
+                    return Err(Fault::InputFailed {
+                        finger,
+                        instruction,
+                    })
+                }
+                (Input::WouldBlock, _) | (Input::Eof, EofPolicy::Block) => {
+                    self.fin = finger;
+                    return Ok(StepOutcome::Blocked);
+                }
+            },
+            Load(Pointers { b, c, .. }) => {
+                let array = self.reg[b];
+                if array > 0 {
+                    let program = self.arrays.get(array).ok_or(Fault::InactiveArray {
+                        finger,
+                        instruction,
+                        array,
+                    })?;
+                    // Reloading the program we are already running keeps
+                    // its decoded instructions
+                    if !Arc::ptr_eq(program, self.arrays.program()) {
+                        let program = Arc::clone(program);
+                        // The copy shares its words until amended, but count
+                        // it in full rather than let a program outgrow its
+                        // limit through loads
+                        let words = self.arrays.stats.live_words
+                            - self.arrays.program().len() as u64
+                            + program.len() as u64;
+                        self.check_limit(Resource::TotalWords, words, Some(instruction))?;
+                        self.arrays.set_program(program);
+                        self.clear_code();
+                    }
+                }
+                self.fin = self.reg[c] as usize;
+            }
+            Ortho(OrthoPointers { a, value }) => self.reg[a] = value,
+        };
+        Ok(StepOutcome::Running)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::asm::boot_words;
+    use crate::io::{BufferIo, ChannelIo, QueueIo};
     use std::sync::mpsc::channel;
     use std::thread;
+    use std::time::Duration;
 
     #[test]
     fn test_instruction() {
         let target = Instruction::Add(Pointers { a: 7, b: 6, c: 0 });
-        let other: Instruction = 0b0011_0000_0000_0000_0000_0001_1111_0000.into();
-        assert_eq!(target, other);
+        let other = Instruction::decode(0b0011_0000_0000_0000_0000_0001_1111_0000);
+        assert_eq!(Some(target), other);
+        assert_eq!(target.to_string(), "add r7, r6, r0");
+        assert_eq!(
+            Instruction::decode(0xD200_0041).unwrap().to_string(),
+            "ortho r1, 65"
+        );
+        assert_eq!(
+            Instruction::decode(0x8000_000A).unwrap().to_string(),
+            "alloc r1, r2"
+        );
+        for &word in &[0x3000_01F0, 0xD200_0041, 0x8000_00CA] {
+            assert_eq!(Instruction::decode(word).unwrap().encode(), word);
+        }
+    }
+
+    fn boot(program: &[u32]) -> Machine {
+        boot_words(program, BufferIo::default())
+    }
+
+    #[test]
+    fn test_faults() {
+        // ortho r1 <- 0; div r0 <- r0 / r1
+        let mut machine = boot(&[0xD200_0000, 0x5000_0001]);
+        assert_eq!(machine.step(), Ok(StepOutcome::Running));
+        let fault = machine.step().unwrap_err();
+        assert_eq!(
+            fault,
+            Fault::DivisionByZero {
+                finger: 1,
+                instruction: Div(Pointers { a: 0, b: 0, c: 1 }),
+            }
+        );
+        assert_eq!(machine.finger(), 1);
+
+        let mut machine = boot(&[0xE000_0000]);
+        assert_eq!(
+            machine.step(),
+            Err(Fault::InvalidOpcode {
+                finger: 0,
+                word: 0xE000_0000
+            })
+        );
+
+        // abandon r0 (which holds 0)
+        let mut machine = boot(&[0x9000_0000]);
+        assert!(matches!(machine.step(), Err(Fault::AbandonProgram { .. })));
+
+        // ortho r0 <- 256; out r0
+        let mut machine = boot(&[0xD000_0100, 0xA000_0000]);
+        machine.step().unwrap();
+        assert_eq!(
+            machine.step().unwrap_err().instruction(),
+            Some(Out(Pointers { a: 0, b: 0, c: 0 }))
+        );
+
+        let mut machine = boot(&[0xD000_0000]);
+        machine.step().unwrap();
+        assert_eq!(machine.step(), Err(Fault::FingerOutOfRange { finger: 1 }));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_spin() {
+        // ortho r0 <- 1; ortho r1 <- 2; halt
+        let mut machine = boot(&[0xD000_0001, 0xD200_0002, 0x7000_0000]);
+        assert_eq!(try_spin(&mut machine), Ok(StepOutcome::Running));
+        let machine = spin(machine).unwrap();
+        assert_eq!(machine.reg[..2], [1, 2]);
+        assert!(spin(machine).is_none());
+    }
+
+    #[test]
+    fn test_run() {
+        // ortho r0 <- 1; load r1 r1 (back to 0); halt
+        let mut machine = boot(&[0xD000_0001, 0xC000_0009, 0x7000_0000]);
+        assert_eq!(machine.run(RunLimit::cycles(5)), RunOutcome::CycleLimit);
+        assert_eq!(machine.cycles(), 5);
+        assert_eq!(machine.finger(), 1);
+
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert_eq!(machine.run(RunLimit::until(deadline)), RunOutcome::Deadline);
+
+        let mut machine = boot(&[0xD000_0001, 0x7000_0000]);
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Halted);
+        assert!(machine.halted());
+        assert_eq!(machine.cycles(), 2);
+        assert_eq!(machine.reg[0], 1);
+        assert_eq!(machine.step(), Ok(StepOutcome::Halted));
+        assert_eq!(machine.cycles(), 2);
+    }
+
+    #[test]
+    fn test_accessors() {
+        // ortho r1 <- 3; alloc r0 <- r1; halt
+        let mut machine = boot(&[0xD200_0003, 0x8000_0001, 0x7000_0000]);
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Halted);
+        assert_eq!(machine.live_arrays().collect::<Vec<_>>(), [(0, 3), (1, 3)]);
+        assert_eq!(machine.registers()[0], 1);
+
+        assert_eq!(machine.set_word(1, 2, 7), Ok(()));
+        assert_eq!(machine.word(1, 2), Some(7));
+        assert_eq!(
+            machine.set_word(1, 3, 7),
+            Err(AccessError::OutOfBounds {
+                array: 1,
+                offset: 3
+            })
+        );
+        assert_eq!(
+            machine.set_word(2, 0, 7),
+            Err(AccessError::InactiveArray(2))
+        );
+
+        // Patch the halt into `ortho r2 <- 9` and run it again
+        machine.set_word(0, 2, 0xD400_0009).unwrap();
+        machine.set_finger(2);
+        assert_eq!(
+            machine.instruction(),
+            Ok(Ortho(OrthoPointers { a: 2, value: 9 }))
+        );
+        assert_eq!(machine.step(), Ok(StepOutcome::Running));
+        assert_eq!(machine.registers()[2], 9);
+        machine.set_register(2, 0).unwrap();
+        assert_eq!(machine.registers()[2], 0);
+        assert_eq!(machine.set_register(8, 0), Err(AccessError::NoRegister(8)));
+    }
+
+    #[test]
+    fn test_abandon() {
+        // ortho r1 <- 3; alloc r0 <- r1; abandon r0; index r2 <- [r0][r3]
+        let mut machine = boot(&[0xD200_0003, 0x8000_0001, 0x9000_0000, 0x1000_0083]);
+        assert_eq!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Fault(Fault::InactiveArray {
+                finger: 3,
+                instruction: Index(Pointers { a: 2, b: 0, c: 3 }),
+                array: 1,
+            })
+        );
+
+        // ortho r1 <- 3; alloc r0 <- r1; abandon r0; abandon r0
+        let mut machine = boot(&[0xD200_0003, 0x8000_0001, 0x9000_0000, 0x9000_0000]);
+        assert!(matches!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Fault(Fault::AbandonInactive {
+                finger: 3,
+                array: 1,
+                ..
+            })
+        ));
+        let stats = machine.array_stats();
+        assert_eq!((stats.live_arrays, stats.live_words), (1, 4));
+        assert_eq!((stats.peak_arrays, stats.peak_words), (2, 7));
+    }
+
+    #[test]
+    fn test_limits() {
+        // ortho r1 <- 3; alloc r0 <- r1; load r2 r2 (back to 0)
+        let program = [0xD200_0003, 0x8000_0001, 0xC000_0092];
+        let limited = |limits| {
+            let mut machine = boot(&program);
+            machine.set_limits(limits);
+            match machine.run(RunLimit::cycles(100)) {
+                RunOutcome::Fault(Fault::LimitExceeded {
+                    finger, resource, ..
+                }) => Some((finger, resource, machine.cycles())),
+                _ => None,
+            }
+        };
+        assert_eq!(limited(Limits::default()), None);
+        let limits = Limits {
+            live_arrays: Some(4),
+            ..Limits::default()
+        };
+        assert_eq!(limited(limits), Some((1, Resource::LiveArrays, 10)));
+        let limits = Limits {
+            total_words: Some(10),
+            ..Limits::default()
+        };
+        assert_eq!(limited(limits), Some((1, Resource::TotalWords, 7)));
+        let limits = Limits {
+            array_words: Some(2),
+            ..Limits::default()
+        };
+        assert_eq!(limited(limits), Some((1, Resource::ArrayWords, 1)));
+        let limits = Limits {
+            cycles: Some(5),
+            ..Limits::default()
+        };
+        assert_eq!(limited(limits), Some((2, Resource::Cycles, 5)));
+
+        // ortho r0 <- 65; out r0; out r0
+        let mut machine = boot(&[0xD000_0041, 0xA000_0000, 0xA000_0000]);
+        machine.set_limits(Limits {
+            output_bytes: Some(1),
+            ..Limits::default()
+        });
+        let fault = machine.step().and(machine.step()).and(machine.step());
+        assert_eq!(
+            fault.unwrap_err().to_string(),
+            "output byte limit of 1 exceeded (finger 0x2)"
+        );
+    }
+
+    #[test]
+    fn test_self_modification() {
+        // ortho r0 <- 1; amend [r4][r1] <- r2; load r4 r4
+        let mut machine = boot(&[0xD000_0001, 0x2000_010A, 0xC000_0024]);
+        // Patch offset 0 to `ortho r0 <- 42` once it has been cached
+        machine.reg[2] = 0xD000_002A;
+        assert_eq!(machine.run(RunLimit::cycles(4)), RunOutcome::CycleLimit);
+        assert_eq!(machine.reg[0], 42);
+    }
+
+    #[test]
+    fn test_load_shares_until_amended() {
+        // load r1 r2
+        let mut machine = boot(&[0xC000_000A]);
+        // amend [r1][r0] <- r3; halt
+        let id = machine.arrays.allocate(2);
+        Arc::make_mut(machine.arrays.get_mut(id).unwrap())
+            .copy_from_slice(&[0x2000_0043, 0x7000_0000]);
+        machine.reg[1] = 1;
+        machine.reg[3] = 0xDEAD_BEEF;
+
+        machine.step().unwrap();
+        let arrays = &machine.arrays;
+        assert!(Arc::ptr_eq(arrays.program(), arrays.get(1).unwrap()));
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Halted);
+        assert_eq!(machine.arrays.program()[0], 0x2000_0043);
+        assert_eq!(machine.arrays.get(1).unwrap()[0], 0xDEAD_BEEF);
+    }
+
+    #[test]
+    fn test_io() {
+        // in r0; out r0; halt
+        let io = QueueIo::new();
+        let mut machine = boot_words(&[0xB000_0000, 0xA000_0000, 0x7000_0000], io.clone());
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Blocked);
+        io.push_input(b"!");
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Halted);
+        assert_eq!(io.drain_output(), b"!");
+    }
+
+    #[test]
+    fn test_end_of_input() {
+        // in r0; in r1; halt
+        let boot = |eof| {
+            let mut machine = boot_words(
+                &[0xB000_0000, 0xB000_0001, 0x7000_0000],
+                BufferIo::new(&[7]),
+            );
+            machine.eof = eof;
+            machine
+        };
+
+        let mut machine = boot(EofPolicy::AllOnes);
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Halted);
+        assert_eq!(machine.reg[..2], [7, 0xFFFF_FFFF]);
+
+        let mut machine = boot(EofPolicy::Block);
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Blocked);
+        assert_eq!(machine.finger(), 1);
+
+        let mut machine = boot(EofPolicy::Fault);
+        assert_eq!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Fault(Fault::EndOfInput {
+                finger: 1,
+                instruction: In(Pointers { a: 0, b: 0, c: 1 }),
+            })
+        );
     }
 
     #[test]
     fn sandmark() {
         let sand_mark = include_bytes!("../static/media/sandmark.umz");
         let (client_sender, client_receiver) = channel();
-        let (machine_sender, machine_receiver) = channel();
-        let mut machine = Machine::new(machine_receiver, client_sender, &mut sand_mark.as_ref());
+        let (_machine_sender, machine_receiver) = channel();
+        let io = ChannelIo::new(machine_receiver, client_sender);
+        let mut machine = Machine::new(io, &mut sand_mark.as_ref());
 
-        thread::spawn(move || {
-            while let Some(m) = spin(machine) {
-                machine = m
-            }
-        });
+        thread::spawn(move || machine.run(RunLimit::default()));
 
         let mut output = String::new();
         while let Ok(i) = client_receiver.recv() {
-            output.push(i as u8 as char);
+            output.push(char::from(i));
             if output.ends_with("loadprog ok.") {
                 println!("{}", output);
-                return
+                return;
             }
         }
 
-        panic!(
-            "Failed with\n{}",
-            output
-        )    
+        panic!("Failed with\n{}", output)
     }
 }
@@ -0,0 +1,204 @@
+//! Finding where a program spends its cycles.
+//!
+//! A `Profiler` is a `Tracer` that counts how often each offset and each
+//! opcode executes. UMIX and the codex run several programs in turn by
+//! `Load`ing them into array 0, so counts are kept per program, named by
+//! the identifier of the array last loaded (0 until the first `Load` of
+//! another array).
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::trace::{TraceEvent, Tracer};
+use crate::{Instruction, Pointers, MNEMONICS};
+
+/// Executions of one offset of one program.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct HotSpot {
+    pub program: u32,
+    pub offset: usize,
+    pub count: u64,
+    /// The instruction last executed there.
+    pub instruction: Instruction,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Profiler {
+    program: u32,
+    cycles: u64,
+    opcodes: [u64; 14],
+    programs: HashMap<u32, u64>,
+    offsets: HashMap<(u32, usize), HotSpot>,
+}
+
+impl Tracer for Profiler {
+    fn trace(&mut self, event: &TraceEvent) {
+        let program = self.program;
+        self.cycles += 1;
+        self.opcodes[event.instruction.opcode() as usize] += 1;
+        *self.programs.entry(program).or_insert(0) += 1;
+        let spot = self
+            .offsets
+            .entry((program, event.finger))
+            .or_insert(HotSpot {
+                program,
+                offset: event.finger,
+                count: 0,
+                instruction: event.instruction,
+            });
+        spot.count += 1;
+        spot.instruction = event.instruction;
+        if let Instruction::Load(Pointers { b, .. }) = event.instruction {
+            if event.before[b] != 0 {
+                self.program = event.before[b];
+            }
+        }
+    }
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    count as f64 * 100.0 / total.max(1) as f64
+}
+
+impl Profiler {
+    /// Instructions profiled.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Executions of each opcode, indexed by opcode.
+    pub fn opcodes(&self) -> [u64; 14] {
+        self.opcodes
+    }
+
+    /// Cycles spent in each program, busiest first.
+    pub fn programs(&self) -> Vec<(u32, u64)> {
+        let mut programs: Vec<(u32, u64)> = self.programs.iter().map(|(&p, &c)| (p, c)).collect();
+        programs.sort_by_key(|&(program, count)| (!count, program));
+        programs
+    }
+
+    /// The `top` most executed offsets, busiest first.
+    pub fn hot_spots(&self, top: usize) -> Vec<HotSpot> {
+        let mut spots: Vec<HotSpot> = self.offsets.values().cloned().collect();
+        spots.sort_by_key(|spot| (!spot.count, spot.program, spot.offset));
+        spots.truncate(top);
+        spots
+    }
+
+    /// Write the cycles per program, the `top` hottest offsets with their
+    /// instructions, and a histogram of opcodes.
+    pub fn report(&self, top: usize, out: &mut dyn Write) -> io::Result<()> {
+        let total = self.cycles;
+        writeln!(out, "{} cycles profiled", total)?;
+
+        writeln!(out, "\nPrograms, by array last loaded:")?;
+        for (program, count) in self.programs() {
+            writeln!(
+                out,
+                "  array {:<10} {:>14} {:>6.1}%",
+                program,
+                count,
+                percent(count, total)
+            )?;
+        }
+
+        writeln!(out, "\nHot offsets:")?;
+        writeln!(
+            out,
+            "  {:>14} {:>7}  {:>10}  {:<8}  instruction",
+            "count", "", "array", "offset"
+        )?;
+        for spot in self.hot_spots(top) {
+            writeln!(
+                out,
+                "  {:>14} {:>6.1}%  {:>10}  {:08x}  {}",
+                spot.count,
+                percent(spot.count, total),
+                spot.program,
+                spot.offset,
+                spot.instruction
+            )?;
+        }
+
+        writeln!(out, "\nOpcodes:")?;
+        let most = self.opcodes.iter().cloned().max().unwrap_or(0);
+        let mut opcodes: Vec<(usize, u64)> = self.opcodes.iter().cloned().enumerate().collect();
+        opcodes.sort_by_key(|&(opcode, count)| (!count, opcode));
+        for (opcode, count) in opcodes.into_iter().filter(|&(_, count)| count > 0) {
+            let bar = (count * 40).div_ceil(most.max(1)) as usize;
+            writeln!(
+                out,
+                "  {:<8} {:>14} {:>6.1}%  {}",
+                MNEMONICS[opcode],
+                count,
+                percent(count, total),
+                "#".repeat(bar)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::asm::boot_words;
+    use crate::io::QueueIo;
+    use crate::{RunLimit, RunOutcome};
+
+    #[test]
+    fn test_profile() {
+        let program = [
+            0xD200_0002u32, // ortho r1, 2
+            0x8000_0011,    // alloc r2, r1
+            0xD600_0000,    // ortho r3, 0
+            0xC000_0013,    // load r2, r3
+        ];
+        let mut machine = boot_words(&program, QueueIo::new());
+        let profiler = Arc::new(Mutex::new(Profiler::default()));
+        machine.set_tracer(Some(Box::new(Arc::clone(&profiler))));
+        // The loaded array is all zeroes, `cmov r0, r0, r0`, and the finger
+        // runs off its end
+        assert!(matches!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Fault(_)
+        ));
+
+        let profiler = profiler.lock().unwrap();
+        assert_eq!(profiler.cycles(), 6);
+        assert_eq!(profiler.programs(), vec![(0, 4), (1, 2)]);
+        assert_eq!(profiler.opcodes()[0], 2);
+        assert_eq!(profiler.opcodes()[12], 1);
+        let hot = profiler.hot_spots(5);
+        assert_eq!(hot.len(), 5);
+        assert_eq!((hot[4].program, hot[4].offset, hot[4].count), (1, 0, 1));
+        assert_eq!(hot[1].instruction.to_string(), "alloc r2, r1");
+
+        let mut report = Vec::new();
+        profiler.report(2, &mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "6 cycles profiled
+
+Programs, by array last loaded:
+  array 0                       4   66.7%
+  array 1                       2   33.3%
+
+Hot offsets:
+           count               array  offset    instruction
+               1   16.7%           0  00000000  ortho r1, 2
+               1   16.7%           0  00000001  alloc r2, r1
+
+Opcodes:
+  cmov                  2   33.3%  ########################################
+  ortho                 2   33.3%  ########################################
+  alloc                 1   16.7%  ####################
+  load                  1   16.7%  ####################
+"
+        );
+    }
+}
@@ -0,0 +1,385 @@
+//! Recording a session and replaying it deterministically.
+//!
+//! While recording, the machine notes every byte `In` consumes together
+//! with the cycle it was consumed on, and every byte `Out` writes. Since
+//! the machine is otherwise deterministic, feeding the same bytes on the
+//! same cycles to the same program reproduces the session exactly;
+//! `replay` does that and reports the first point where it does not.
+//!
+//! Recordings are framed by the magic `UMREC` as described in `codec`.
+//! Version 1's payload, all big-endian, is the scroll hash (`u32`), the
+//! arguments (`u32` count of `u32`-length strings), the start and end cycles
+//! (`u64`), the input (`u32` count of a `u64` cycle, a byte and an end of
+//! input flag) and the output (`u32` length and bytes).
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::codec::{self, put_bytes, put_u32, put_u64, Malformed};
+use crate::io::QueueIo;
+use crate::Instruction::In;
+use crate::{Machine, RunLimit, RunOutcome, StepOutcome};
+
+const MAGIC: &[u8; 5] = b"UMREC";
+const VERSION: u16 = 1;
+
+/// A read by `In`: the byte, or `None` where it saw the end of input.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct InputEvent {
+    pub cycle: u64,
+    pub byte: Option<u8>,
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Recording {
+    /// CRC-32 of array 0, as big-endian words, when recording started.
+    pub scroll_hash: u32,
+    /// Whatever the host was started with, for reference.
+    pub args: Vec<String>,
+    pub start_cycle: u64,
+    pub end_cycle: u64,
+    pub input: Vec<InputEvent>,
+    pub output: Vec<u8>,
+}
+
+/// Why a recording could not be read.
+#[derive(Debug)]
+pub enum RecordingError {
+    Io(io::Error),
+    NotARecording,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RecordingError::*;
+        match self {
+            Io(e) => write!(f, "could not read recording: {}", e),
+            NotARecording => write!(f, "not a session recording"),
+            UnsupportedVersion(version) => write!(f, "unsupported recording version {}", version),
+            ChecksumMismatch => write!(f, "recording checksum mismatch"),
+            Corrupt(what) => write!(f, "corrupt recording: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
+impl From<io::Error> for RecordingError {
+    fn from(e: io::Error) -> Self {
+        RecordingError::Io(e)
+    }
+}
+
+impl From<Malformed> for RecordingError {
+    fn from(e: Malformed) -> Self {
+        match e {
+            Malformed::NotRecognised => RecordingError::NotARecording,
+            Malformed::UnsupportedVersion(version) => RecordingError::UnsupportedVersion(version),
+            Malformed::ChecksumMismatch => RecordingError::ChecksumMismatch,
+            Malformed::Corrupt(what) => RecordingError::Corrupt(what),
+        }
+    }
+}
+
+impl Recording {
+    pub fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let mut out = codec::frame(MAGIC, VERSION);
+        put_u32(&mut out, self.scroll_hash);
+        put_u32(&mut out, self.args.len() as u32);
+        for arg in &self.args {
+            put_bytes(&mut out, arg.as_bytes());
+        }
+        put_u64(&mut out, self.start_cycle);
+        put_u64(&mut out, self.end_cycle);
+        put_u32(&mut out, self.input.len() as u32);
+        for event in &self.input {
+            put_u64(&mut out, event.cycle);
+            out.push(event.byte.unwrap_or(0));
+            out.push(event.byte.is_none() as u8);
+        }
+        put_bytes(&mut out, &self.output);
+        codec::seal(&mut out);
+        writer.write_all(&out)
+    }
+
+    pub fn read(reader: &mut dyn Read) -> Result<Self, RecordingError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut payload = codec::open(&bytes, MAGIC, VERSION)?;
+        let scroll_hash = payload.u32()?;
+        let mut args = Vec::new();
+        for _ in 0..payload.u32()? {
+            let arg = String::from_utf8(payload.bytes()?.to_vec())
+                .map_err(|_| RecordingError::Corrupt("argument is not UTF-8"))?;
+            args.push(arg);
+        }
+        let start_cycle = payload.u64()?;
+        let end_cycle = payload.u64()?;
+        let mut input = Vec::new();
+        for _ in 0..payload.u32()? {
+            let cycle = payload.u64()?;
+            let byte = payload.u8()?;
+            let eof = payload.flag()?;
+            input.push(InputEvent {
+                cycle,
+                byte: if eof { None } else { Some(byte) },
+            });
+        }
+        let output = payload.bytes()?.to_vec();
+        payload.finish()?;
+        Ok(Recording {
+            scroll_hash,
+            args,
+            start_cycle,
+            end_cycle,
+            input,
+            output,
+        })
+    }
+}
+
+fn program_hash(machine: &Machine) -> u32 {
+    let bytes: Vec<u8> = machine
+        .arrays
+        .program()
+        .iter()
+        .flat_map(|word| word.to_be_bytes().to_vec())
+        .collect();
+    codec::crc32(&bytes)
+}
+
+impl Machine {
+    /// Start noting input and output, replacing any recording in progress.
+    pub fn start_recording(&mut self, args: Vec<String>) {
+        self.recording = Some(Recording {
+            scroll_hash: program_hash(self),
+            args,
+            start_cycle: self.cycles,
+            ..Recording::default()
+        });
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        let mut recording = self.recording.take()?;
+        recording.end_cycle = self.cycles;
+        Some(recording)
+    }
+}
+
+/// Where a replay first parted from its recording.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Divergence {
+    /// Array 0 is not the program that was recorded.
+    Scroll { recorded: u32, found: u32 },
+    /// The machine is not at the cycle the recording started on.
+    Start { recorded: u64, found: u64 },
+    /// The recording has input for this cycle but the machine is not on
+    /// an `In` instruction.
+    InputNotRead { cycle: u64 },
+    /// The machine wanted input the recording does not have.
+    InputWanted { cycle: u64 },
+    /// Output differs from the recording at this byte.
+    Output { cycle: u64, offset: usize },
+    /// The machine halted or faulted before the recording ended.
+    Stopped { cycle: u64, outcome: RunOutcome },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Divergence::*;
+        match self {
+            Scroll { recorded, found } => write!(
+                f,
+                "scroll hash {:#010x} does not match the recorded {:#010x}",
+                found, recorded
+            ),
+            Start { recorded, found } => write!(
+                f,
+                "machine is at cycle {}, the recording starts at {}",
+                found, recorded
+            ),
+            InputNotRead { cycle } => write!(f, "recorded input not read at cycle {}", cycle),
+            InputWanted { cycle } => write!(f, "unrecorded input wanted at cycle {}", cycle),
+            Output { cycle, offset } => {
+                write!(f, "output byte {} differs by cycle {}", offset, cycle)
+            }
+            Stopped { cycle, outcome } => write!(f, "stopped at cycle {}: {:?}", cycle, outcome),
+        }
+    }
+}
+
+/// Re-run a recording on `machine`, which must be in the state recording
+/// started from and read its input through `io`. Input is fed on exactly
+/// the recorded cycles and output is checked as it is produced.
+pub fn replay(
+    machine: &mut Machine,
+    io: &QueueIo,
+    recording: &Recording,
+) -> Result<(), Divergence> {
+    let found = program_hash(machine);
+    if found != recording.scroll_hash {
+        return Err(Divergence::Scroll {
+            recorded: recording.scroll_hash,
+            found,
+        });
+    }
+    if machine.cycles != recording.start_cycle {
+        return Err(Divergence::Start {
+            recorded: recording.start_cycle,
+            found: machine.cycles,
+        });
+    }
+
+    let mut output = Vec::new();
+    let mut check_output = |machine: &Machine| {
+        output.extend(io.drain_output());
+        let expected = &recording.output;
+        match output.iter().zip(expected).position(|(a, b)| a != b) {
+            Some(offset) => Err(offset),
+            None if output.len() > expected.len() => Err(expected.len()),
+            None => Ok(()),
+        }
+        .map_err(|offset| Divergence::Output {
+            cycle: machine.cycles,
+            offset,
+        })
+    };
+    let mut run_to = |machine: &mut Machine, cycle: u64| {
+        let outcome = machine.run(RunLimit::cycles(cycle.saturating_sub(machine.cycles)));
+        check_output(machine)?;
+        match outcome {
+            _ if machine.cycles == cycle => Ok(()),
+            RunOutcome::Blocked => Err(Divergence::InputWanted {
+                cycle: machine.cycles,
+            }),
+            outcome => Err(Divergence::Stopped {
+                cycle: machine.cycles,
+                outcome,
+            }),
+        }
+    };
+
+    for event in &recording.input {
+        run_to(machine, event.cycle)?;
+        if !matches!(machine.instruction(), Ok(In(_))) {
+            return Err(Divergence::InputNotRead { cycle: event.cycle });
+        }
+        match event.byte {
+            Some(byte) => io.push_input(&[byte]),
+            None => io.close(),
+        }
+        match machine.step() {
+            Ok(StepOutcome::Blocked) => {
+                return Err(Divergence::InputNotRead { cycle: event.cycle });
+            }
+            Ok(_) => {}
+            Err(fault) => {
+                return Err(Divergence::Stopped {
+                    cycle: machine.cycles,
+                    outcome: RunOutcome::Fault(fault),
+                });
+            }
+        }
+        if io.queued_input() > 0 {
+            return Err(Divergence::InputNotRead { cycle: event.cycle });
+        }
+    }
+    run_to(machine, recording.end_cycle)?;
+    // Anything recorded but never produced
+    if output.len() < recording.output.len() {
+        return Err(Divergence::Output {
+            cycle: machine.cycles,
+            offset: output.len(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asm::boot_words;
+    use crate::{EofPolicy, Fault, Pointers};
+
+    // in r0; out r0; ortho r1 <- 1; load r2 r2 (back to 0)
+    const ECHO: [u32; 4] = [0xB000_0000, 0xA000_0000, 0xD200_0001, 0xC000_0092];
+
+    fn boot(program: &[u32]) -> (Machine, QueueIo) {
+        let io = QueueIo::new();
+        (boot_words(program, io.clone()), io)
+    }
+
+    fn record(program: &[u32]) -> Recording {
+        let (mut machine, io) = boot(program);
+        machine.start_recording(vec!["guest".into()]);
+        io.push_input(b"hi");
+        machine.run(RunLimit::cycles(20));
+        io.push_input(b"!");
+        machine.run(RunLimit::default());
+        machine.stop_recording().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let recording = record(&ECHO);
+        assert_eq!(recording.output, b"hi!");
+        assert_eq!(
+            recording.input[2],
+            InputEvent {
+                cycle: 8,
+                byte: Some(b'!')
+            }
+        );
+        assert_eq!(recording.end_cycle, 12);
+
+        let mut bytes = Vec::new();
+        recording.write(&mut bytes).unwrap();
+        assert_eq!(Recording::read(&mut bytes.as_slice()).unwrap(), recording);
+    }
+
+    #[test]
+    fn test_replay() {
+        let recording = record(&ECHO);
+        let (mut machine, io) = boot(&ECHO);
+        assert_eq!(replay(&mut machine, &io, &recording), Ok(()));
+
+        let mut tampered = recording.clone();
+        tampered.output[1] = b'o';
+        let (mut machine, io) = boot(&ECHO);
+        assert_eq!(
+            replay(&mut machine, &io, &tampered),
+            Err(Divergence::Output {
+                cycle: 8,
+                offset: 1
+            })
+        );
+
+        let mut tampered = recording.clone();
+        tampered.input[1].cycle -= 1;
+        let (mut machine, io) = boot(&ECHO);
+        assert_eq!(
+            replay(&mut machine, &io, &tampered),
+            Err(Divergence::InputNotRead { cycle: 3 })
+        );
+
+        // Reading the recorded end of input faults under this policy
+        let mut tampered = recording;
+        tampered.input[2].byte = None;
+        let (mut machine, io) = boot(&ECHO);
+        machine.eof = EofPolicy::Fault;
+        assert_eq!(
+            replay(&mut machine, &io, &tampered),
+            Err(Divergence::Stopped {
+                cycle: 8,
+                outcome: RunOutcome::Fault(Fault::EndOfInput {
+                    finger: 0,
+                    instruction: In(Pointers { a: 0, b: 0, c: 0 }),
+                }),
+            })
+        );
+    }
+}
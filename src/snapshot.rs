@@ -0,0 +1,325 @@
+//! Saving a `Machine` to bytes and resuming it later.
+//!
+//! A snapshot is framed by the magic `UMSNAP`, a version and a checksum as
+//! described in `codec`. Version 1's payload, all big-endian, is:
+//!
+//! - finger (`u64`), the eight registers (`u32`), cycles (`u64`)
+//! - halted and the `EofPolicy` (one byte each), output bytes so far (`u64`)
+//! - the five `Limits`, each a presence byte and a `u64`
+//! - the `ArrayStats` counters (six `u64`)
+//! - every array identifier (`u32` count), each a presence byte followed,
+//!   when live, by its length (`u32`) and words
+//! - the free list (`u32` count of `u32` identifiers), most recent last
+//! - pending input and buffered output (`u32` length and bytes each)
+//!
+//! Execution engine settings (decode cache, threaded code, JIT) are not
+//! part of the machine's state and come back at their defaults.
+//!
+//! `to_text` and `from_text` carry a snapshot through places that only hold
+//! strings, such as a browser's local storage, as base64.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use crate::arrays::{ArrayStats, Arrays};
+use crate::codec::{self, put_bytes, put_u32, put_u64, Malformed};
+use crate::io::UmIo;
+use crate::{EofPolicy, Limits, Machine};
+
+const MAGIC: &[u8; 6] = b"UMSNAP";
+const VERSION: u16 = 1;
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Why a snapshot could not be restored.
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    NotASnapshot,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SnapshotError::*;
+        match self {
+            Io(e) => write!(f, "could not read snapshot: {}", e),
+            NotASnapshot => write!(f, "not a machine snapshot"),
+            UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}", version),
+            ChecksumMismatch => write!(f, "snapshot checksum mismatch"),
+            Corrupt(what) => write!(f, "corrupt snapshot: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<Malformed> for SnapshotError {
+    fn from(e: Malformed) -> Self {
+        match e {
+            Malformed::NotRecognised => SnapshotError::NotASnapshot,
+            Malformed::UnsupportedVersion(version) => SnapshotError::UnsupportedVersion(version),
+            Malformed::ChecksumMismatch => SnapshotError::ChecksumMismatch,
+            Malformed::Corrupt(what) => SnapshotError::Corrupt(what),
+        }
+    }
+}
+
+impl Machine {
+    /// Write the whole machine, including whatever its I/O has pending,
+    /// as a snapshot.
+    pub fn save(&mut self, writer: &mut dyn Write) -> io::Result<()> {
+        let mut out = codec::frame(MAGIC, VERSION);
+
+        put_u64(&mut out, self.fin as u64);
+        for &register in &self.reg {
+            put_u32(&mut out, register);
+        }
+        put_u64(&mut out, self.cycles);
+        out.push(self.halted as u8);
+        out.push(match self.eof {
+            EofPolicy::AllOnes => 0,
+            EofPolicy::Block => 1,
+            EofPolicy::Fault => 2,
+        });
+        put_u64(&mut out, self.output_bytes);
+        let limits = self.limits;
+        for limit in [
+            limits.live_arrays,
+            limits.total_words,
+            limits.array_words,
+            limits.output_bytes,
+            limits.cycles,
+        ] {
+            out.push(limit.is_some() as u8);
+            put_u64(&mut out, limit.unwrap_or(0));
+        }
+        let stats = self.arrays.stats;
+        for counter in [
+            stats.live_arrays,
+            stats.live_words,
+            stats.peak_arrays,
+            stats.peak_words,
+            stats.allocations,
+            stats.abandons,
+        ] {
+            put_u64(&mut out, counter);
+        }
+
+        put_u32(&mut out, self.arrays.slots().len() as u32);
+        for slot in self.arrays.slots() {
+            out.push(slot.is_some() as u8);
+            if let Some(array) = slot {
+                put_u32(&mut out, array.len() as u32);
+                for &word in array.iter() {
+                    put_u32(&mut out, word);
+                }
+            }
+        }
+        put_u32(&mut out, self.arrays.free().len() as u32);
+        for &id in self.arrays.free() {
+            put_u32(&mut out, id);
+        }
+        put_bytes(&mut out, &self.io.pending_input());
+        put_bytes(&mut out, &self.io.buffered_output());
+        codec::seal(&mut out);
+        writer.write_all(&out)
+    }
+
+    /// Resume a machine from a snapshot written by `save`, handing its
+    /// pending input and buffered output back to `io`.
+    pub fn restore(io: impl UmIo + 'static, reader: &mut dyn Read) -> Result<Self, SnapshotError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut payload = codec::open(&bytes, MAGIC, VERSION)?;
+        let fin = payload.u64()? as usize;
+        let mut reg = [0; 8];
+        for register in reg.iter_mut() {
+            *register = payload.u32()?;
+        }
+        let cycles = payload.u64()?;
+        let halted = payload.flag()?;
+        let eof = match payload.u8()? {
+            0 => EofPolicy::AllOnes,
+            1 => EofPolicy::Block,
+            2 => EofPolicy::Fault,
+            _ => return Err(SnapshotError::Corrupt("unknown EOF policy")),
+        };
+        let output_bytes = payload.u64()?;
+        let mut limit = || -> Result<Option<u64>, SnapshotError> {
+            let present = payload.flag()?;
+            let value = payload.u64()?;
+            Ok(if present { Some(value) } else { None })
+        };
+        let limits = Limits {
+            live_arrays: limit()?,
+            total_words: limit()?,
+            array_words: limit()?,
+            output_bytes: limit()?,
+            cycles: limit()?,
+        };
+        let stats = ArrayStats {
+            live_arrays: payload.u64()?,
+            live_words: payload.u64()?,
+            peak_arrays: payload.u64()?,
+            peak_words: payload.u64()?,
+            allocations: payload.u64()?,
+            abandons: payload.u64()?,
+        };
+
+        let count = payload.u32()?;
+        let mut slots = Vec::new();
+        for _ in 0..count {
+            if !payload.flag()? {
+                slots.push(None);
+                continue;
+            }
+            let len = payload.u32()? as usize;
+            if len > payload.bytes.len() / 4 {
+                return Err(SnapshotError::Corrupt("truncated"));
+            }
+            let mut array = Vec::with_capacity(len);
+            for _ in 0..len {
+                array.push(payload.u32()?);
+            }
+            slots.push(Some(Arc::new(array)));
+        }
+        let count = payload.u32()?;
+        let mut free = Vec::new();
+        for _ in 0..count {
+            free.push(payload.u32()?);
+        }
+        let arrays = Arrays::from_parts(slots, free, stats)
+            .ok_or(SnapshotError::Corrupt("inconsistent free list"))?;
+        let pending_input = payload.bytes()?;
+        let buffered_output = payload.bytes()?;
+        payload.finish()?;
+
+        let mut machine = Machine::with_eof_policy(io, &mut io::empty(), eof);
+        machine.fin = fin;
+        machine.reg = reg;
+        machine.cycles = cycles;
+        machine.halted = halted;
+        machine.limits = limits;
+        machine.output_bytes = output_bytes;
+        machine.arrays = arrays;
+        machine.io.restore(pending_input, buffered_output);
+        Ok(machine)
+    }
+}
+
+/// Encode a snapshot as padded base64.
+pub fn to_text(snapshot: &[u8]) -> String {
+    let mut text = String::with_capacity(snapshot.len().div_ceil(3) * 4);
+    for chunk in snapshot.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, &byte)| bits | u32::from(byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(char::from(BASE64[((bits >> (18 - 6 * i)) & 63) as usize]));
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+/// Decode base64 written by `to_text`, or `None` if it is not base64.
+pub fn from_text(text: &str) -> Option<Vec<u8>> {
+    let mut snapshot = Vec::with_capacity(text.len() / 4 * 3);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = (bits << 6 | value) & 0xFFFF;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            snapshot.push((bits >> count) as u8);
+        }
+    }
+    // A lone character past the last whole byte cannot come from an encoder
+    if count >= 6 {
+        return None;
+    }
+    Some(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asm::boot_words;
+    use crate::io::QueueIo;
+    use crate::{RunLimit, RunOutcome};
+
+    #[test]
+    fn test_round_trip() {
+        // ortho r1 <- 3; alloc r0 <- r1; in r2; out r2; load r3 r3 (back to 0)
+        let program = [
+            0xD200_0003,
+            0x8000_0001,
+            0xB000_0002,
+            0xA000_0002,
+            0xC000_00DB,
+        ];
+        let io = QueueIo::new();
+        let mut machine = boot_words(&program, io.clone());
+        io.push_input(b"abcd");
+        assert_eq!(machine.run(RunLimit::cycles(7)), RunOutcome::CycleLimit);
+
+        let mut snapshot = Vec::new();
+        machine.save(&mut snapshot).unwrap();
+        let resumed_io = QueueIo::new();
+        let mut resumed = Machine::restore(resumed_io.clone(), &mut snapshot.as_slice()).unwrap();
+        assert_eq!(resumed.finger(), machine.finger());
+        assert_eq!(resumed.cycles(), machine.cycles());
+        assert_eq!(resumed.arrays, machine.arrays);
+
+        for (machine, io) in [(&mut machine, &io), (&mut resumed, &resumed_io)] {
+            assert_eq!(machine.run(RunLimit::default()), RunOutcome::Blocked);
+            assert_eq!(io.drain_output(), b"abcd");
+        }
+
+        snapshot[20] ^= 1;
+        assert!(matches!(
+            Machine::restore(QueueIo::new(), &mut snapshot.as_slice()),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+    }
+    #[test]
+    fn test_text() {
+        assert_eq!(to_text(b""), "");
+        assert_eq!(to_text(b"f"), "Zg==");
+        assert_eq!(to_text(b"fo"), "Zm8=");
+        assert_eq!(to_text(b"foo"), "Zm9v");
+        assert_eq!(to_text(&[0xFB, 0xFF]), "+/8=");
+        for text in ["", "Zg==", "Zm8=", "Zm9v", "+/8="] {
+            assert_eq!(to_text(&from_text(text).unwrap()), text);
+        }
+        assert_eq!(from_text("Zm9v!"), None);
+        assert_eq!(from_text("Zm9vZ"), None);
+
+        // ortho r1 <- 'A'; out r1; halt
+        let io = QueueIo::new();
+        let mut machine = boot_words(&[0xD200_0041, 0xA000_0001, 0x7000_0000], io.clone());
+        assert_eq!(machine.run(RunLimit::cycles(2)), RunOutcome::CycleLimit);
+        let mut snapshot = Vec::new();
+        machine.save(&mut snapshot).unwrap();
+        let text = to_text(&snapshot);
+        let mut resumed =
+            Machine::restore(QueueIo::new(), &mut from_text(&text).unwrap().as_slice()).unwrap();
+        assert_eq!(resumed.finger(), 2);
+        assert_eq!(resumed.run(RunLimit::default()), RunOutcome::Halted);
+    }
+}
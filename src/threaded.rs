@@ -0,0 +1,311 @@
+//! Threaded-code execution of array 0.
+//!
+//! A block is the straight run of instructions starting at some offset up
+//! to the next `Load`, or up to but not including the next `Halt`, `In` or
+//! undecodable platter. Each instruction is translated once into a closure
+//! with its registers already resolved, so running a block skips the fetch,
+//! decode and dispatch the interpreter pays every cycle. Halting, input and
+//! faults are left to `Machine::step`.
+//!
+//! Amending array 0 drops every block covering the amended offset and
+//! loading a new program drops them all; the interpreter covers the gap
+//! until the blocks are translated again.
+
+use std::sync::Arc;
+
+use crate::Instruction::*;
+use crate::{Fault, Instruction, Machine, OrthoPointers, Pointers};
+
+// Send and Sync so that blocks, and the machine holding them, can move
+// between threads
+type Op = Box<dyn Fn(&mut Machine) -> Result<(), Fault> + Send + Sync>;
+
+struct Block {
+    start: usize,
+    ops: Vec<Op>,
+    // A trailing `Load` moves the finger itself
+    jumps: bool,
+}
+
+impl Block {
+    fn covers(&self, offset: usize) -> bool {
+        offset >= self.start && offset < self.start + self.ops.len()
+    }
+}
+
+/// How much translation the threaded engine has done.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct ThreadedStats {
+    pub blocks_translated: u64,
+    pub blocks_invalidated: u64,
+    pub instructions_translated: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Blocks {
+    // Indexed by the offset each block starts at
+    blocks: Vec<Option<Arc<Block>>>,
+    // Indexed by offset, the starts of blocks that cover it; entries for
+    // blocks since invalidated are skipped
+    owners: Vec<Vec<usize>>,
+    // Bumped on every invalidation so a running block can tell that it
+    // may have been overwritten underneath itself
+    generation: u64,
+    pub(crate) stats: ThreadedStats,
+}
+
+impl Blocks {
+    pub(crate) fn invalidate(&mut self, offset: usize) {
+        let owners = match self.owners.get_mut(offset) {
+            Some(owners) if !owners.is_empty() => std::mem::take(owners),
+            _ => return,
+        };
+        let mut removed = 0;
+        for start in owners {
+            let slot = &mut self.blocks[start];
+            if slot.as_ref().is_some_and(|block| block.covers(offset)) {
+                *slot = None;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            self.stats.blocks_invalidated += removed;
+            self.generation += 1;
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.stats.blocks_invalidated += self.blocks.iter().flatten().count() as u64;
+        self.blocks.clear();
+        self.owners.clear();
+        self.generation += 1;
+    }
+
+    fn get(&self, start: usize) -> Option<Arc<Block>> {
+        self.blocks.get(start).and_then(Clone::clone)
+    }
+
+    fn insert(&mut self, block: Arc<Block>) {
+        if self.blocks.len() <= block.start {
+            self.blocks.resize(block.start + 1, None);
+        }
+        self.stats.blocks_translated += 1;
+        self.stats.instructions_translated += block.ops.len() as u64;
+        let (start, end) = (block.start, block.start + block.ops.len());
+        self.blocks[start] = Some(block);
+        if self.owners.len() < end {
+            self.owners.resize(end, Vec::new());
+        }
+        for owners in &mut self.owners[start..end] {
+            if !owners.contains(&start) {
+                owners.push(start);
+            }
+        }
+    }
+}
+
+fn translate(program: &[u32], start: usize) -> Block {
+    let mut ops = Vec::new();
+    let mut jumps = false;
+    for (offset, &word) in program.iter().enumerate().skip(start) {
+        match Instruction::decode(word) {
+            None | Some(Halt(_)) | Some(In(_)) => break,
+            Some(instruction @ Load(_)) => {
+                ops.push(op(offset, instruction));
+                jumps = true;
+                break;
+            }
+            Some(instruction) => ops.push(op(offset, instruction)),
+        }
+    }
+    Block { start, ops, jumps }
+}
+
+fn op(finger: usize, instruction: Instruction) -> Op {
+    match instruction {
+        Move(Pointers { a, b, c }) => Box::new(move |m| {
+            if m.reg[c] > 0 {
+                m.reg[a] = m.reg[b];
+            }
+            Ok(())
+        }),
+        Add(Pointers { a, b, c }) => Box::new(move |m| {
+            m.reg[a] = m.reg[b].wrapping_add(m.reg[c]);
+            Ok(())
+        }),
+        Mul(Pointers { a, b, c }) => Box::new(move |m| {
+            m.reg[a] = m.reg[b].wrapping_mul(m.reg[c]);
+            Ok(())
+        }),
+        Nand(Pointers { a, b, c }) => Box::new(move |m| {
+            m.reg[a] = !(m.reg[b] & m.reg[c]);
+            Ok(())
+        }),
+        Ortho(OrthoPointers { a, value }) => Box::new(move |m| {
+            m.reg[a] = value;
+            Ok(())
+        }),
+        Index(Pointers { a, b, c }) => Box::new(move |m| {
+            match m
+                .arrays
+                .get(m.reg[b])
+                .and_then(|stack| stack.get(m.reg[c] as usize))
+            {
+                Some(&value) => {
+                    m.reg[a] = value;
+                    Ok(())
+                }
+                None => interpret(m, finger, instruction),
+            }
+        }),
+        // Anything that allocates, writes to arrays or produces output
+        // is rare enough to hand to the interpreter
+        _ => Box::new(move |m| interpret(m, finger, instruction)),
+    }
+}
+
+fn interpret(machine: &mut Machine, finger: usize, instruction: Instruction) -> Result<(), Fault> {
+    machine.fin = finger + 1;
+    machine.execute(instruction).map(|_| ())
+}
+
+/// Run the block at the finger, executing at most `budget` instructions.
+/// Returns how many were executed; zero means the caller should step the
+/// interpreter instead.
+pub(crate) fn run_block(machine: &mut Machine, budget: u64) -> Result<u64, Fault> {
+    if machine.halted {
+        return Ok(0);
+    }
+    let start = machine.fin;
+    let block = match machine.blocks.get(start) {
+        Some(block) => block,
+        None => {
+            let block = Arc::new(translate(machine.arrays.program(), start));
+            // The finger is on a terminator, there is nothing worth keeping
+            if block.ops.is_empty() {
+                return Ok(0);
+            }
+            machine.blocks.insert(Arc::clone(&block));
+            block
+        }
+    };
+    if block.ops.len() as u64 > budget {
+        return Ok(0);
+    }
+
+    let generation = machine.blocks.generation;
+    let mut executed = 0;
+    for op in block.ops.iter() {
+        if let Err(fault) = op(machine) {
+            machine.fin = start + executed;
+            machine.cycles += executed as u64;
+            return Err(fault);
+        }
+        executed += 1;
+        if machine.blocks.generation != generation {
+            break;
+        }
+    }
+    if !(block.jumps && executed == block.ops.len()) {
+        machine.fin = start + executed;
+    }
+    machine.cycles += executed as u64;
+    Ok(executed as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asm::boot_words;
+    use crate::io::BufferIo;
+    use crate::{RunLimit, RunOutcome};
+
+    fn boot(program: &[u32]) -> Machine {
+        let mut machine = boot_words(program, BufferIo::default());
+        machine.set_threaded(true);
+        machine
+    }
+
+    #[test]
+    fn test_matches_interpreter() {
+        // ortho r1 <- 3; ortho r2 <- 4; mul r3 <- r1 * r2; add r3 <- r3 + r1;
+        // ortho r0 <- 1; nand r4 <- r0 & r0; halt
+        let program = [
+            0xD200_0003,
+            0xD400_0004,
+            0x4000_00CA,
+            0x3000_00D9,
+            0xD000_0001,
+            0x6000_0100,
+            0x7000_0000,
+        ];
+        let mut threaded = boot(&program);
+        assert_eq!(threaded.run(RunLimit::default()), RunOutcome::Halted);
+        let mut plain = boot(&program);
+        plain.set_threaded(false);
+        assert_eq!(plain.run(RunLimit::default()), RunOutcome::Halted);
+
+        assert_eq!(threaded.reg, plain.reg);
+        assert_eq!(threaded.cycles(), plain.cycles());
+        assert_eq!(threaded.reg[3], 15);
+        assert_eq!(threaded.threaded_stats().blocks_translated, 1);
+    }
+
+    #[test]
+    fn test_amend_invalidates() {
+        // ortho r0 <- 1; amend [r4][r1] <- r2; load r4 r4
+        let mut machine = boot(&[0xD000_0001, 0x2000_010A, 0xC000_0024]);
+        machine.reg[2] = 0xD000_002A;
+        assert_eq!(machine.run(RunLimit::cycles(4)), RunOutcome::CycleLimit);
+        assert_eq!(machine.reg[0], 42);
+        assert_eq!(machine.threaded_stats().blocks_invalidated, 1);
+    }
+
+    #[test]
+    fn test_invalidate_covering() {
+        // ortho r2 <- 2; load r0 r2 (on to the next); ortho r3 <- 3; halt
+        let mut machine = boot(&[0xD400_0002, 0xC000_0002, 0xD600_0003, 0x7000_0000]);
+        assert_eq!(machine.run(RunLimit::default()), RunOutcome::Halted);
+        assert_eq!(machine.threaded_stats().blocks_translated, 2);
+        // The halt and past the end of the program
+        machine.blocks.invalidate(3);
+        machine.blocks.invalidate(7);
+        assert_eq!(machine.threaded_stats().blocks_invalidated, 0);
+        machine.blocks.invalidate(2);
+        machine.blocks.invalidate(2);
+        assert_eq!(machine.threaded_stats().blocks_invalidated, 1);
+        assert!(machine.blocks.get(0).is_some());
+        assert!(machine.blocks.get(2).is_none());
+    }
+
+    #[test]
+    fn test_fault_in_block() {
+        // ortho r1 <- 0; div r0 <- r0 / r1
+        let mut machine = boot(&[0xD200_0000, 0x5000_0001]);
+        assert!(matches!(
+            machine.run(RunLimit::default()),
+            RunOutcome::Fault(Fault::DivisionByZero { finger: 1, .. })
+        ));
+        assert_eq!(machine.finger(), 1);
+        assert_eq!(machine.cycles(), 1);
+    }
+
+    #[test]
+    fn sandmark() {
+        let sand_mark = include_bytes!("../static/media/sandmark.umz");
+        let io = BufferIo::default();
+        let mut machine = Machine::new(io.clone(), &mut sand_mark.as_ref());
+        machine.set_threaded(true);
+        loop {
+            let outcome = machine.run(RunLimit::cycles(100_000));
+            let output = String::from_utf8_lossy(&io.output()).into_owned();
+            if output.contains("loadprog ok.") {
+                return;
+            }
+            if outcome != RunOutcome::CycleLimit {
+                panic!("Failed with\n{}", output)
+            }
+        }
+    }
+}
@@ -0,0 +1,452 @@
+//! Watching every instruction a machine executes.
+//!
+//! A `Tracer` given to `Machine::set_tracer` sees each instruction once it
+//! has executed, with the registers before and after. While one is set the
+//! machine steps one instruction at a time, bypassing the threaded engine
+//! and the JIT.
+//!
+//! `TraceWriter` logs events that pass a `Filter` as text, one per line, or
+//! in a compact binary form for long runs that `TraceReader` reads back.
+//! The binary form is the magic `UMTRACE` and a big-endian `u16` version,
+//! then one record per event:
+//!
+//! - the cycle, as a LEB128 count of cycles since the previous record
+//!   (or since cycle 0 for the first)
+//! - the finger (LEB128) and the instruction's platter (big-endian `u32`)
+//! - a byte with a bit set for each register that changed between the
+//!   previous record and this one, followed by the new values (`u32` each)
+//! - a byte with a bit set for each register the instruction changed,
+//!   followed by the values it left (`u32` each)
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use crate::Instruction;
+
+const MAGIC: &[u8; 7] = b"UMTRACE";
+const VERSION: u16 = 1;
+
+/// An instruction that ran.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TraceEvent {
+    /// Cycles executed before this instruction.
+    pub cycle: u64,
+    pub finger: usize,
+    pub instruction: Instruction,
+    pub before: [u32; 8],
+    pub after: [u32; 8],
+}
+
+/// The cycle, finger and instruction, the registers beforehand and those
+/// the instruction changed, e.g.
+/// `12 00000003  add r1, r1, r2  00000000 00000005 ... -> r1=00000007`.
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:08x}  {:<24}",
+            self.cycle,
+            self.finger,
+            self.instruction.to_string()
+        )?;
+        for value in &self.before {
+            write!(f, " {:08x}", value)?;
+        }
+        write!(f, " ->")?;
+        for (r, (before, after)) in self.before.iter().zip(&self.after).enumerate() {
+            if before != after {
+                write!(f, " r{}={:08x}", r, after)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tracers are `Send` so that a machine carrying one can still be handed to
+/// another thread.
+pub trait Tracer: Send {
+    fn trace(&mut self, event: &TraceEvent);
+
+    /// Sees the program in array 0 when tracing starts and again after
+    /// each `Load` of another array, with the identifier of the array it
+    /// came from (0 when tracing starts).
+    fn program(&mut self, _array: u32, _program: &Arc<Vec<u32>>) {}
+}
+
+/// Keeps every event.
+impl Tracer for Vec<TraceEvent> {
+    fn trace(&mut self, event: &TraceEvent) {
+        self.push(*event);
+    }
+}
+
+/// Lets the caller keep a handle on a tracer the machine owns.
+impl<T: Tracer> Tracer for Arc<Mutex<T>> {
+    fn trace(&mut self, event: &TraceEvent) {
+        self.lock().unwrap().trace(event);
+    }
+
+    fn program(&mut self, array: u32, program: &Arc<Vec<u32>>) {
+        self.lock().unwrap().program(array, program);
+    }
+}
+
+/// Both tracers see every event.
+impl<A: Tracer, B: Tracer> Tracer for (A, B) {
+    fn trace(&mut self, event: &TraceEvent) {
+        self.0.trace(event);
+        self.1.trace(event);
+    }
+
+    fn program(&mut self, array: u32, program: &Arc<Vec<u32>>) {
+        self.0.program(array, program);
+        self.1.program(array, program);
+    }
+}
+
+/// Every tracer sees every event, in order.
+impl Tracer for Vec<Box<dyn Tracer>> {
+    fn trace(&mut self, event: &TraceEvent) {
+        for tracer in self {
+            tracer.trace(event);
+        }
+    }
+
+    fn program(&mut self, array: u32, program: &Arc<Vec<u32>>) {
+        for tracer in self {
+            tracer.program(array, program);
+        }
+    }
+}
+
+/// Which events are worth keeping. `None` lets everything through.
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    pub fingers: Option<RangeInclusive<usize>>,
+    pub opcodes: Option<Vec<u32>>,
+    pub cycles: Option<RangeInclusive<u64>>,
+}
+
+impl Filter {
+    pub fn matches(&self, event: &TraceEvent) -> bool {
+        self.fingers
+            .as_ref()
+            .is_none_or(|fingers| fingers.contains(&event.finger))
+            && self
+                .opcodes
+                .as_ref()
+                .is_none_or(|opcodes| opcodes.contains(&event.instruction.opcode()))
+            && self
+                .cycles
+                .as_ref()
+                .is_none_or(|cycles| cycles.contains(&event.cycle))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
+    Text,
+    Binary,
+}
+
+fn put_leb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// A bit per register that differs, then the values in `to` that do.
+fn put_changes(out: &mut Vec<u8>, from: &[u32; 8], to: &[u32; 8]) {
+    let mask = (0..8)
+        .filter(|&r| from[r] != to[r])
+        .fold(0u8, |mask, r| mask | 1 << r);
+    out.push(mask);
+    for r in (0..8).filter(|&r| mask & 1 << r != 0) {
+        out.extend_from_slice(&to[r].to_be_bytes());
+    }
+}
+
+/// Writes the events a filter lets through. Write errors stop the trace
+/// and are reported by `finish`.
+pub struct TraceWriter<W: Write> {
+    out: W,
+    filter: Filter,
+    format: Format,
+    last_cycle: u64,
+    registers: [u32; 8],
+    buffer: Vec<u8>,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(mut out: W, format: Format, filter: Filter) -> io::Result<Self> {
+        if format == Format::Binary {
+            out.write_all(MAGIC)?;
+            out.write_all(&VERSION.to_be_bytes())?;
+        }
+        Ok(TraceWriter {
+            out,
+            filter,
+            format,
+            last_cycle: 0,
+            registers: [0; 8],
+            buffer: Vec::new(),
+            error: None,
+        })
+    }
+
+    /// Flush the trace, reporting the first error writing it.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+
+    fn write(&mut self, event: &TraceEvent) -> io::Result<()> {
+        if self.format == Format::Text {
+            return writeln!(self.out, "{}", event);
+        }
+        self.buffer.clear();
+        put_leb128(&mut self.buffer, event.cycle - self.last_cycle);
+        put_leb128(&mut self.buffer, event.finger as u64);
+        self.buffer
+            .extend_from_slice(&event.instruction.encode().to_be_bytes());
+        put_changes(&mut self.buffer, &self.registers, &event.before);
+        put_changes(&mut self.buffer, &event.before, &event.after);
+        self.last_cycle = event.cycle;
+        self.registers = event.after;
+        self.out.write_all(&self.buffer)
+    }
+}
+
+impl<W: Write + Send> Tracer for TraceWriter<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        if self.error.is_some() || !self.filter.matches(event) {
+            return;
+        }
+        if let Err(e) = self.write(event) {
+            self.error = Some(e);
+        }
+    }
+}
+
+/// Why a binary trace could not be read.
+#[derive(Debug)]
+pub enum TraceError {
+    Io(io::Error),
+    NotATrace,
+    UnsupportedVersion(u16),
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::TraceError::*;
+        match self {
+            Io(e) => write!(f, "could not read trace: {}", e),
+            NotATrace => write!(f, "not a binary trace"),
+            UnsupportedVersion(version) => write!(f, "unsupported trace version {}", version),
+            Corrupt(what) => write!(f, "corrupt trace: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl From<io::Error> for TraceError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => TraceError::Corrupt("truncated"),
+            _ => TraceError::Io(e),
+        }
+    }
+}
+
+/// The events of a binary trace, in order.
+pub struct TraceReader<R: Read> {
+    input: R,
+    last_cycle: u64,
+    registers: [u32; 8],
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut input: R) -> Result<Self, TraceError> {
+        let mut header = [0; 9];
+        input.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => TraceError::NotATrace,
+            _ => TraceError::Io(e),
+        })?;
+        if &header[..7] != MAGIC {
+            return Err(TraceError::NotATrace);
+        }
+        let version = u16::from_be_bytes([header[7], header[8]]);
+        if version != VERSION {
+            return Err(TraceError::UnsupportedVersion(version));
+        }
+        Ok(TraceReader {
+            input,
+            last_cycle: 0,
+            registers: [0; 8],
+        })
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.input.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        self.input.read_exact(&mut bytes)?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn leb128(&mut self) -> Result<u64, TraceError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(TraceError::Corrupt("number too long"))
+    }
+
+    fn changes(&mut self, registers: &mut [u32; 8]) -> io::Result<()> {
+        let mask = self.u8()?;
+        for (r, register) in registers.iter_mut().enumerate() {
+            if mask & 1 << r != 0 {
+                *register = self.u32()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn event(&mut self, first: u8) -> Result<TraceEvent, TraceError> {
+        // The first byte of the record was read to look for the end
+        let mut delta = u64::from(first & 0x7F);
+        if first & 0x80 != 0 {
+            delta |= self.leb128()?.checked_shl(7).unwrap_or(0);
+        }
+        let cycle = self.last_cycle + delta;
+        let finger = self.leb128()? as usize;
+        let instruction =
+            Instruction::decode(self.u32()?).ok_or(TraceError::Corrupt("invalid instruction"))?;
+        let mut before = self.registers;
+        self.changes(&mut before)?;
+        let mut after = before;
+        self.changes(&mut after)?;
+        self.last_cycle = cycle;
+        self.registers = after;
+        Ok(TraceEvent {
+            cycle,
+            finger,
+            instruction,
+            before,
+            after,
+        })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceEvent, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut first = [0];
+        match self.input.read(&mut first) {
+            Ok(0) => None,
+            Ok(_) => Some(self.event(first[0])),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asm::boot_words;
+    use crate::io::QueueIo;
+    use crate::{Machine, RunLimit};
+
+    // ortho r1 <- 300; ortho r2 <- 1; add r3 <- r3 + r2; cmov r4 <- r3 if r3;
+    // ortho r0 <- 2; load r5 r0 (back to the add)
+    const COUNT: [u32; 6] = [
+        0xD200_012C,
+        0xD400_0001,
+        0x3000_00DA,
+        0x0000_011B,
+        0xD000_0002,
+        0xC000_0028,
+    ];
+
+    fn run(tracer: impl Tracer + 'static, cycles: u64) -> Machine {
+        let mut machine = boot_words(&COUNT, QueueIo::new());
+        machine.set_tracer(Some(Box::new(tracer)));
+        machine.run(RunLimit::cycles(cycles));
+        machine
+    }
+
+    #[test]
+    fn test_events() {
+        let events: Arc<Mutex<Vec<TraceEvent>>> = Arc::default();
+        run(Arc::clone(&events), 5);
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[2].cycle, 2);
+        assert_eq!(events[2].finger, 2);
+        assert_eq!(events[2].before[3], 0);
+        assert_eq!(events[2].after[3], 1);
+        assert_eq!(
+            events[2].to_string(),
+            "2 00000002  add r3, r3, r2           00000000 0000012c 00000001 00000000 \
+             00000000 00000000 00000000 00000000 -> r3=00000001"
+        );
+    }
+
+    #[test]
+    fn test_binary() {
+        let filter = Filter {
+            opcodes: Some(vec![3, 0]),
+            cycles: Some(10..=100),
+            ..Filter::default()
+        };
+        let writer = TraceWriter::new(Vec::new(), Format::Binary, filter.clone()).unwrap();
+        let writer = Arc::new(Mutex::new(writer));
+        run(Arc::clone(&writer), 200);
+        let all: Arc<Mutex<Vec<TraceEvent>>> = Arc::default();
+        run(Arc::clone(&all), 200);
+        let expected: Vec<TraceEvent> = all
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| filter.matches(event))
+            .cloned()
+            .collect();
+        assert_eq!(expected.len(), 46);
+
+        writer.lock().unwrap().finish().unwrap();
+        let bytes = writer.lock().unwrap().out.clone();
+        assert!(bytes.len() < expected.len() * 16);
+        let read: Vec<TraceEvent> = TraceReader::new(bytes.as_slice())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, expected);
+
+        let truncated = &bytes[..bytes.len() - 1];
+        let last = TraceReader::new(truncated).unwrap().last().unwrap();
+        assert!(matches!(last, Err(TraceError::Corrupt("truncated"))));
+    }
+}
@@ -1,9 +1,8 @@
-use std::sync::mpsc::{channel, Receiver, Sender};
-
 use http::request::Request as HttpRequest;
 use http::response::Response as HttpResponse;
 
-use crate::{spin, Machine};
+use crate::io::QueueIo;
+use crate::{Fault, Machine, RunLimit, RunOutcome};
 use failure::Error;
 
 use serde_derive::{Deserialize, Serialize};
@@ -15,29 +14,38 @@ use yew::worker::*;
 use stdweb::web::Date;
 
 struct MachineWrapper {
-    to_machine: Sender<u32>,
-    to_client: Receiver<u32>,
+    io: QueueIo,
     machine: Machine,
+    // The fault that stopped the machine, kept so it can still be inspected
+    fault: Option<Fault>,
 }
 
 impl MachineWrapper {
-    fn run(mut self, iters: usize) -> Option<(usize, Self)> {
-        let mut last_finger = 0;
-        let mut cycles = 0;
-        for _ in 0..iters {
-            if let Some(machine) = spin(self.machine) {
-                self.machine = machine;
-                if self.machine.finger() == last_finger {
-                    cycles -= 1;
-                    return Some((cycles, self));
-                }
-            } else {
-                return None;
+    fn new(io: QueueIo, machine: Machine) -> Self {
+        MachineWrapper {
+            io,
+            machine,
+            fault: None,
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.machine.halted() || self.fault.is_some()
+    }
+
+    /// Run up to `iters` cycles and return how many ran, along with the
+    /// fault if the machine hit one.
+    fn run(&mut self, iters: usize) -> (usize, Option<Fault>) {
+        let start = self.machine.cycles();
+        let outcome = self.machine.run(RunLimit::cycles(iters as u64));
+        let cycles = (self.machine.cycles() - start) as usize;
+        match outcome {
+            RunOutcome::Fault(fault) => {
+                self.fault = Some(fault.clone());
+                (cycles, Some(fault))
             }
-            cycles += 1;
-            last_finger = self.machine.finger();
+            _ => (cycles, None),
         }
-        Some((cycles, self))
     }
 }
 
@@ -46,6 +54,8 @@ pub struct WebMachine {
     ticker: IntervalTask,
     machine: Option<MachineWrapper>,
     buffer: Vec<u32>,
+    // A fault not yet reported, sent along with the next status
+    fault: Option<Fault>,
     clock: usize,
     cycles: usize,
     media_fetcher: Option<FetchTask>,
@@ -58,6 +68,10 @@ pub enum Request {
     Input(Vec<u32>),
     Shutdown,
     Status,
+    /// Answered with `Response::Snapshot`
+    Snapshot,
+    /// Boot from the bytes of a `Response::Snapshot`
+    Restore(Vec<u8>),
 }
 
 impl Transferable for Request {}
@@ -71,6 +85,9 @@ pub enum Response {
         clock: usize,
         output: Vec<u32>,
     },
+    /// The running machine as a snapshot, or `None` if nothing is running
+    Snapshot(Option<Vec<u8>>),
+    Error(String),
 }
 
 impl Transferable for Response {}
@@ -111,6 +128,7 @@ impl Agent for WebMachine {
             ticker,
             machine: None,
             buffer: Vec::new(),
+            fault: None,
             clock: 100_000,
             cycles: 0,
             media_fetcher: None,
@@ -122,32 +140,32 @@ impl Agent for WebMachine {
     fn update(&mut self, msg: Self::Message) {
         match msg {
             MachineMsg::Tick => {
-                if let Some(wrapper) = self.machine.take() {
-                    let start = Date::now();
-                    if let Some((cycles, wrapper)) = wrapper.run(self.clock) {
-                        if cycles == self.clock {
-                            self.set_clock(start);
-                        }
-                        self.cycles += cycles;
-                        while let Ok(i) = wrapper.to_client.try_recv() {
-                            self.buffer.push(i);
-                        }
-                        self.machine = Some(wrapper);
+                let clock = self.clock;
+                let start = Date::now();
+                let ran = match self.machine.as_mut() {
+                    Some(wrapper) if !wrapper.stopped() => {
+                        let (cycles, fault) = wrapper.run(clock);
+                        Some((cycles, fault, wrapper.io.drain_output()))
                     }
+                    _ => None,
                 };
+                if let Some((cycles, fault, output)) = ran {
+                    if cycles == clock {
+                        self.set_clock(start);
+                    }
+                    self.cycles += cycles;
+                    self.buffer.extend(output.into_iter().map(u32::from));
+                    self.fault = fault;
+                }
             }
             MachineMsg::BootAs(u) => {
                 self.media_fetcher = None;
-                let (client_sender, to_client) = channel();
-                let (to_machine, machine_receiver) = channel();
-                let machine = Machine::new(machine_receiver, client_sender, &mut u.as_slice());
+                let io = QueueIo::new();
+                let machine = Machine::new(io.clone(), &mut u.as_slice());
                 self.cycles = 0;
                 self.buffer = Vec::new();
-                self.machine = Some(MachineWrapper {
-                    machine,
-                    to_machine,
-                    to_client,
-                });
+                self.fault = None;
+                self.machine = Some(MachineWrapper::new(io, machine));
             }
         }
     }
@@ -155,26 +173,34 @@ impl Agent for WebMachine {
     // Handle incoming messages from components of other agents.
     fn handle(&mut self, msg: Self::Input, who: HandlerId) {
         match msg {
-            Request::Status => self.link.response(
-                who,
-                Response::Status {
-                    finger: self
-                        .machine
-                        .as_ref()
-                        .map(|w| w.machine.finger())
-                        .unwrap_or_default(),
-                    halted: self.machine.is_none(),
-                    cycles: self.cycles,
-                    clock: self.clock,
-                    output: self.buffer.drain(0..).collect(),
-                },
-            ),
+            Request::Status => {
+                self.link.response(
+                    who,
+                    Response::Status {
+                        finger: self
+                            .machine
+                            .as_ref()
+                            .map(|w| w.machine.finger())
+                            .unwrap_or_default(),
+                        halted: self
+                            .machine
+                            .as_ref()
+                            .map(MachineWrapper::stopped)
+                            .unwrap_or(true),
+                        cycles: self.cycles,
+                        clock: self.clock,
+                        output: self.buffer.drain(0..).collect(),
+                    },
+                );
+                if let Some(fault) = self.fault.take() {
+                    self.link
+                        .response(who, Response::Error(format!("Machine fault: {}", fault)));
+                }
+            }
             Request::Input(u) => {
                 if let Some(wrapper) = self.machine.as_ref() {
-                    for v in u {
-                        wrapper.to_machine.send(v).unwrap();
-
-                    }
+                    let bytes: Vec<u8> = u.into_iter().map(|v| v as u8).collect();
+                    wrapper.io.push_input(&bytes);
                 }
             }
 
@@ -182,15 +208,37 @@ impl Agent for WebMachine {
                 if self.media_fetcher.is_none() {
                     self.cycles = 0;
                     self.buffer = Vec::new();
+                    self.fault = None;
                     self.machine = None;
                     let req = HttpRequest::get(url).body(Nothing).unwrap();
                     self.media_fetcher =
                         Some(FetchService::new().fetch_binary(req, self.media_callback.clone()));
                 }
             },
+            Request::Snapshot => {
+                let snapshot = self.machine.as_mut().and_then(|wrapper| {
+                    let mut bytes = Vec::new();
+                    wrapper.machine.save(&mut bytes).ok().map(|_| bytes)
+                });
+                self.link.response(who, Response::Snapshot(snapshot));
+            }
+            Request::Restore(bytes) => {
+                let io = QueueIo::new();
+                match Machine::restore(io.clone(), &mut bytes.as_slice()) {
+                    Ok(machine) => {
+                        self.media_fetcher = None;
+                        self.cycles = machine.cycles() as usize;
+                        self.buffer = Vec::new();
+                        self.fault = None;
+                        self.machine = Some(MachineWrapper::new(io, machine));
+                    }
+                    Err(e) => self.link.response(who, Response::Error(e.to_string())),
+                }
+            }
             Request::Shutdown => {
                 self.cycles = 0;
                 self.buffer = Vec::new();
+                self.fault = None;
                 self.machine = None;
             }
         }